@@ -0,0 +1,89 @@
+use crate::codegen::CodeGen;
+use crate::ir::{self, IrError};
+
+/*
+ * Assemble a program written in the IR text format, e.g.
+ *
+ *   loop: LOAD sym0
+ *         JMZ end
+ *         LOADL 1,0
+ *   end:  HALT
+ *   sym0: DEC 0
+ *
+ * The result is encoded by `CodeGen`, so functions bracketed by FUNC and
+ * RET and the DEC cells are relocated exactly as for compiled programs.
+ * Every FUNC must be closed by a RET.
+ */
+pub fn assemble(source: &str) -> Result<CodeGen, Vec<IrError>> {
+    let mut lines = ir::parse(source)?;
+    let mut codegen = CodeGen::new();
+    // `ir::parse` has already reported these errors against their lines.
+    codegen.gen(&mut lines).map_err(|kind| vec![IrError { line: 0, kind }])?;
+    Ok(codegen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::BufferIo;
+    use crate::ir::{IrErrorKind, Line, IR};
+    use crate::vm::VM;
+
+    fn errors(source: &str) -> Vec<IrError> {
+        assemble(source).err().expect("expected assembly errors")
+    }
+
+    #[test]
+    fn example_program_runs() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/asm1.lir");
+        let codegen = assemble(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let mut vm = VM::with_io(BufferIo::new(""));
+        vm.load_object(&codegen.object());
+        vm.run().unwrap();
+        assert_eq!(vm.io().output(), "3\n2\n1\n9000000\n");
+    }
+
+    #[test]
+    fn functions_are_relocated() {
+        let codegen = assemble("CALL f\nHALT\nf: FUNC\nENTER 1,0\nRET\nx: DEC 0").unwrap();
+        assert_eq!(codegen.code_len, 5);
+        assert_eq!(codegen.symbol_table["f"], 2);
+        assert_eq!(codegen.symbol_table["x"], 5);
+    }
+
+    #[test]
+    fn unclosed_function() {
+        assert_eq!(errors("JMP f\nf: FUNC\nHALT"), vec![IrError {
+            line: 2,
+            kind: IrErrorKind::UnclosedFunc,
+        }]);
+        assert_eq!(errors("FUNC\nLOADC 1\nWRITE\nHALT"), vec![IrError {
+            line: 1,
+            kind: IrErrorKind::UnclosedFunc,
+        }]);
+    }
+
+    #[test]
+    fn nested_functions_are_closed_innermost_first() {
+        let errs = errors("CALL f\nHALT\nf: FUNC\ng: FUNC\nRET\nHALT");
+        assert_eq!(errs, vec![IrError { line: 3, kind: IrErrorKind::UnclosedFunc }]);
+        assert!(assemble("CALL f\nHALT\nf: FUNC\ng: FUNC\nRET\nRET").is_ok());
+    }
+
+    #[test]
+    fn ret_outside_a_function_is_an_instruction() {
+        let codegen = assemble("CALL f\nHALT\nf: RET").unwrap();
+        assert_eq!(codegen.code_len, 3);
+    }
+
+    #[test]
+    fn codegen_reports_what_parse_would() {
+        let mut lines = vec![Line::new(None, IR::JMP("f".to_string())), Line::new(None, IR::HALT)];
+        let err = CodeGen::new().gen(&mut lines).unwrap_err();
+        assert_eq!(err, IrErrorKind::UndefinedLabel("f".to_string()));
+
+        let mut lines = vec![Line::new(None, IR::StartFunc), Line::new(None, IR::HALT)];
+        let err = CodeGen::new().gen(&mut lines).unwrap_err();
+        assert_eq!(err, IrErrorKind::UnclosedFunc);
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Type {
     Bang,                   // !   X
@@ -7,8 +9,10 @@ pub enum Type {
     Const,     // reserved
     Do,        // reserved
     Dot,                    // .   X
+    Else,      // reserved
     End,       // reserved
     Equal,                  // =   X
+    Function,  // reserved
     Greater,                // >   X
     GreaterEqual,           // >=  X
     Hash,                   // #   X
@@ -23,6 +27,7 @@ pub enum Type {
     Plus,                   // +   X
     Procedure, // reserved
     Question,               // ?   X
+    Return,    // reserved
     RightParen,             // )   X
     ColonEqual,             // :=  X
     Semicolon,              // ;   X
@@ -35,52 +40,155 @@ pub enum Type {
     EOF,
 }
 
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Type::Bang => "!",
+            Type::Begin => "begin",
+            Type::Call => "call",
+            Type::Comma => ",",
+            Type::Const => "const",
+            Type::Do => "do",
+            Type::Dot => ".",
+            Type::Else => "else",
+            Type::End => "end",
+            Type::Equal => "=",
+            Type::Function => "function",
+            Type::Greater => ">",
+            Type::GreaterEqual => ">=",
+            Type::Hash => "#",
+            Type::Identifier => "identifier",
+            Type::If => "if",
+            Type::LeftParen => "(",
+            Type::Less => "<",
+            Type::LessEqual => "<=",
+            Type::Minus => "-",
+            Type::Number => "number",
+            Type::Odd => "odd",
+            Type::Plus => "+",
+            Type::Procedure => "procedure",
+            Type::Question => "?",
+            Type::Return => "return",
+            Type::RightParen => ")",
+            Type::ColonEqual => ":=",
+            Type::Semicolon => ";",
+            Type::Slash => "/",
+            Type::Star => "*",
+            Type::Then => "then",
+            Type::Var => "var",
+            Type::While => "while",
+            Type::EOF => "end of file",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Literal {
     Number(i32)
 }
 
+/*
+ * Location of a piece of source text. `offset` and `len` are in bytes,
+ * `line` and `column` are 1-based and counted in characters.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    pub fn new(offset: usize, len: usize, line: u32, column: u32) -> Span {
+        Span { offset, len, line, column }
+    }
+}
+
+impl Span {
+    /*
+     * The span covering everything from the start of `self` to the end
+     * of `other`.
+     */
+    pub fn to(&self, other: Span) -> Span {
+        let end = other.offset + other.len;
+        Span::new(self.offset,
+                  end.saturating_sub(self.offset),
+                  self.line,
+                  self.column)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Token {
     pub r#type: Type,
     pub lexeme: String,
     pub literal: Option<Literal>,
-    pub line: u32
+    pub span: Span
 }
 
 impl Token {
     pub fn new(r#type: Type,
            lexeme: String,
            literal: Option<Literal>,
-           line: u32) -> Token {
+           span: Span) -> Token {
         Token {
-            r#type, lexeme, literal, line
+            r#type, lexeme, literal, span
         }
     }
 }
 
+/*
+ * An AST node together with the source text it was parsed from.
+ */
+#[derive(Clone, Debug)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Spanned<T> {
+        Spanned { node, span }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Expr {
-    Expr(Box<Expr>, Type, Box<Expr>),
+    Call(String, Vec<Spanned<Expr>>),
+    Expr(Box<Spanned<Expr>>, Type, Box<Spanned<Expr>>),
     Literal(Literal),
-    OddExpr(Box<Expr>),
-    PrefixExpr(Option<Type>, Box<Expr>),
+    OddExpr(Box<Spanned<Expr>>),
+    PrefixExpr(Option<Type>, Box<Spanned<Expr>>),
     Var(String),
-    Group(Box<Expr>),
+    Group(Box<Spanned<Expr>>),
 }
 
 #[derive(Clone, Debug)]
 pub enum Block {
-    Assign(Expr, Expr),
-    Begin(Vec<Block>),
-    Block(Box<Block>, Box<Block>, Vec<Block>, Box<Block>),
-    Call(Expr),
-    Const(Expr, Expr),
-    ConstDecs(Vec<Block>),
-    If(Expr, Box<Block>),
-    Procedure(Expr, Box<Block>),
-    Program(Box<Block>),
-    VarDecs(Vec<Expr>),
-    While(Expr, Box<Block>),
-    WriteLn(Expr),
+    Assign(Spanned<Expr>, Spanned<Expr>),
+    Begin(Vec<Spanned<Block>>),
+    Block(Box<Spanned<Block>>,
+          Box<Spanned<Block>>,
+          Vec<Spanned<Block>>,
+          Box<Spanned<Block>>),
+    Call(Spanned<Expr>, Vec<Spanned<Expr>>),
+    Const(Spanned<Expr>, Spanned<Expr>),
+    ConstDecs(Vec<Spanned<Block>>),
+    Function(Spanned<Expr>, Vec<Spanned<Expr>>, Box<Spanned<Block>>),
+    If(Spanned<Expr>, Box<Spanned<Block>>, Option<Box<Spanned<Block>>>),
+    Procedure(Spanned<Expr>, Vec<Spanned<Expr>>, Box<Spanned<Block>>),
+    Program(Box<Spanned<Block>>),
+    Read(Spanned<Expr>),
+    Return(Spanned<Expr>),
+    VarDecs(Vec<Spanned<Expr>>),
+    While(Spanned<Expr>, Box<Spanned<Block>>),
+    WriteLn(Spanned<Expr>),
 }
@@ -1,11 +1,110 @@
 use std::collections::HashMap;
-use std::convert::TryInto;
-use crate::ir::{IR, Line};
+use std::io::{self, Write};
+use crate::ir::{IR, IrErrorKind, Label, Line};
+use crate::opcode;
+
+pub const OBJECT_MAGIC: [u8; 4] = *b"LZB\0";
+pub const OBJECT_VERSION: u32 = 2;
+
+/*
+ * A compiled program as stored in a .lzb file. All fields are written as
+ * little-endian u32 words:
+ *
+ *   magic "LZB\0", version, entry, code length, data length, symbol count
+ *   code words
+ *   data words
+ *   symbols: address, name length in bytes, name padded to a whole word
+ *   line table: entry count, then an address and a line per entry
+ *
+ * The data section is loaded directly after the code section. Version 1
+ * files end after the symbols and have no line table.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Object {
+    pub entry: u32,
+    pub code: Vec<u32>,
+    pub data: Vec<u32>,
+    pub symbols: Vec<(String, u32)>,
+    pub lines: LineTable,
+}
+
+impl Object {
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&OBJECT_MAGIC)?;
+        let header = [OBJECT_VERSION,
+                      self.entry,
+                      self.code.len() as u32,
+                      self.data.len() as u32,
+                      self.symbols.len() as u32];
+        for word in header.iter().chain(&self.code).chain(&self.data) {
+            w.write_all(&word.to_le_bytes())?;
+        }
+
+        for (name, address) in self.symbols.iter() {
+            w.write_all(&address.to_le_bytes())?;
+            w.write_all(&(name.len() as u32).to_le_bytes())?;
+            w.write_all(name.as_bytes())?;
+            let padding = (4 - name.len() % 4) % 4;
+            w.write_all(&[0; 3][..padding])?;
+        }
+
+        w.write_all(&(self.lines.entries.len() as u32).to_le_bytes())?;
+        for (address, line) in self.lines.entries.iter() {
+            w.write_all(&address.to_le_bytes())?;
+            w.write_all(&line.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Maps code addresses to the source lines they were generated from. Each
+ * entry starts a run of words from one line that lasts until the next
+ * entry. Line 0 marks words with no source line, such as data.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineTable {
+    pub entries: Vec<(u32, u32)>,
+}
+
+impl LineTable {
+    /*
+     * Record that the words from `address` on belong to `line`.
+     */
+    pub fn add(&mut self, address: u32, line: u32) {
+        match self.entries.last() {
+            Some(&(_, last)) if last == line => (),
+            _ => self.entries.push((address, line)),
+        }
+    }
+
+    pub fn line(&self, pc: u32) -> Option<u32> {
+        let i = self.entries.partition_point(|&(address, _)| address <= pc);
+        match i {
+            0 => None,
+            _ => Some(self.entries[i - 1].1).filter(|&line| line != 0),
+        }
+    }
+
+    /*
+     * The first address of every run of code generated for `line`.
+     */
+    pub fn addresses(&self, line: u32) -> Vec<u32> {
+        self.entries.iter()
+            .filter(|&&(_, l)| l == line && line != 0)
+            .map(|&(address, _)| address)
+            .collect()
+    }
+}
 
 #[derive(Default)]
 pub struct CodeGen {
-    symbol_table: HashMap<String, u32>,
+    pub symbol_table: HashMap<String, u32>,
     pub output: Vec<u32>,
+    pub line_table: LineTable,
+    // Number of words of `output` that are code rather than data.
+    pub code_len: u32,
     address: u32,
 }
 
@@ -17,138 +116,213 @@ impl CodeGen {
         CodeGen {
             symbol_table,
             output,
+            line_table: LineTable::default(),
+            code_len: 0,
             address
         }
     }
 
-    pub fn gen(&mut self, input: &mut Vec<Line>) {
-        // Relocate data reserved for variables.
-        let mut reorder = Vec::new();
-        let mut i = 0;
-        while i < input.len() {
-            let line = input[i].clone();
-            if let Line { inst: IR::DEC(_), .. } = line {
-                reorder.push(line);
-                input.remove(i);
-            } else {
-                i += 1;
-            }
+    /*
+     * Package the generated code as an object file, splitting the data
+     * cells from the instructions.
+     */
+    pub fn object(&self) -> Object {
+        let (code, data) = self.output.split_at(self.code_len as usize);
+        let mut symbols: Vec<(String, u32)> = self.symbol_table.iter()
+            .map(|(name, address)| (name.clone(), *address))
+            .collect();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+
+        Object {
+            entry: 0,
+            code: code.to_vec(),
+            data: data.to_vec(),
+            symbols,
+            lines: self.line_table.clone(),
         }
-        input.append(&mut reorder);
+    }
+
+    /*
+     * Encode `input`, which is reordered in place. Fails when a FUNC is
+     * never closed by a RET or an operand names a label that is not
+     * defined.
+     */
+    pub fn gen(&mut self, input: &mut Vec<Line>) -> Result<(), IrErrorKind> {
+        let mut i;
 
-        // Relocate functions.
+        // Relocate functions. Nested procedures are emitted inside the body
+        // of their parent, so each one is collected into its own buffer and
+        // the bodies are laid out one after another.
         let mut functions = Vec::new();
+        let mut open: Vec<Vec<Line>> = Vec::new();
         i = 0;
-        let mut in_func = false;
         while i < input.len() {
             let line = input[i].clone();
             if let Line { inst: IR::StartFunc, .. } = line {
-                in_func = true;
+                open.push(Vec::new());
             }
 
-            if in_func {
-                functions.push(line.clone());
+            if let Some(func) = open.last_mut() {
+                func.push(line.clone());
                 input.remove(i);
             } else {
                 i += 1;
             }
 
             if let Line { inst: IR::RET, .. } = line {
-                in_func = false;
+                if let Some(mut func) = open.pop() {
+                    functions.append(&mut func);
+                }
             }
         }
+        if !open.is_empty() {
+            return Err(IrErrorKind::UnclosedFunc);
+        }
         input.append(&mut functions);
 
-        // Gather addresses of symbols.
-        for i in input.iter() {
-            let label = i.label.clone();
+        // Relocate data reserved for variables after all of the code.
+        let mut reorder = Vec::new();
+        i = 0;
+        while i < input.len() {
+            let line = input[i].clone();
+            if let Line { inst: IR::DEC(_), .. } = line {
+                reorder.push(line);
+                input.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        let data_len = reorder.len() as u32;
+        input.append(&mut reorder);
 
-            if label.is_some() {
-                let label = label.unwrap();
+        // Gather addresses of symbols and source lines.
+        for i in input.iter() {
+            if let Some(label) = i.label.clone() {
                 self.symbol_table.insert(label, self.address);
             }
+            self.line_table.add(self.address, i.span.map_or(0, |span| span.line));
 
-            self.address += 1;
+            self.address += CodeGen::size(&i.inst);
         }
+        self.code_len = self.address - data_len;
 
         for i in input.iter() {
             let inst = i.inst.clone();
             match inst {
                 IR::JMP(l) => {
-                    let jmp_addr = self.symbol_table.get(&l);
-                    self.output.push(0x1000_0000 | jmp_addr.unwrap());
+                    let jmp_addr = self.symbol(&l)?;
+                    self.output.push(opcode::encode(opcode::JMP, jmp_addr));
                 },
                 IR::JMZ(l) => {
-                    let jmp_addr = self.symbol_table.get(&l);
-                    self.output.push(0x2000_0000 | jmp_addr.unwrap());
+                    let jmp_addr = self.symbol(&l)?;
+                    self.output.push(opcode::encode(opcode::JMZ, jmp_addr));
                 },
                 IR::LOAD(l) => {
-                    let load_addr = self.symbol_table.get(&l);
-                    self.output.push(0x3000_0000 | load_addr.unwrap());
+                    let load_addr = self.symbol(&l)?;
+                    self.output.push(opcode::encode(opcode::LOAD, load_addr));
                 },
                 IR::LOADC(n) => {
-                    self.output.push((0x4000_0000 | n).try_into().unwrap());
+                    if (-0x80_0000..0x80_0000).contains(&n) {
+                        self.output.push(opcode::encode(opcode::LOADC, n as u32));
+                    } else {
+                        self.output.push(opcode::encode(opcode::LOADW, 0));
+                        self.output.push(n as u32);
+                    }
+                },
+                IR::LOADL(depth, offset) => {
+                    let operand = opcode::frame_operand(depth, offset);
+                    self.output.push(opcode::encode(opcode::LOADL, operand));
                 },
                 IR::STORE(l) => {
-                    let store_addr = self.symbol_table.get(&l);
-                    self.output.push(0x5000_0000 | store_addr.unwrap());
+                    let store_addr = self.symbol(&l)?;
+                    self.output.push(opcode::encode(opcode::STORE, store_addr));
+                },
+                IR::STOREL(depth, offset) => {
+                    let operand = opcode::frame_operand(depth, offset);
+                    self.output.push(opcode::encode(opcode::STOREL, operand));
                 },
                 IR::CALL(l) => {
-                    let func_addr = self.symbol_table.get(&l);
-                    self.output.push(0x6000_0000 | func_addr.unwrap());
+                    let func_addr = self.symbol(&l)?;
+                    self.output.push(opcode::encode(opcode::CALL, func_addr));
                 },
                 IR::WRITE => {
-                    self.output.push(0x7000_0000);
+                    self.output.push(opcode::encode(opcode::WRITE, 0));
                 },
                 IR::ADD => {
-                    self.output.push(0x8000_0000);
+                    self.output.push(opcode::encode(opcode::ADD, 0));
                 },
                 IR::SUB => {
-                    self.output.push(0x9000_0000);
+                    self.output.push(opcode::encode(opcode::SUB, 0));
                 },
                 IR::DIV => {
-                    self.output.push(0xA000_0000);
+                    self.output.push(opcode::encode(opcode::DIV, 0));
                 },
                 IR::MUL => {
-                    self.output.push(0xB000_0000);
+                    self.output.push(opcode::encode(opcode::MUL, 0));
                 },
                 IR::ODD => {
-                    self.output.push(0xC000_0000);
+                    self.output.push(opcode::encode(opcode::ODD, 0));
                 },
                 IR::LT => {
-                    self.output.push(0xD000_0000);
+                    self.output.push(opcode::encode(opcode::LT, 0));
                 },
                 IR::LTE => {
-                    self.output.push(0xE000_0000);
+                    self.output.push(opcode::encode(opcode::LTE, 0));
                 },
                 IR::GT => {
-                    self.output.push(0xF000_0000);
+                    self.output.push(opcode::encode(opcode::GT, 0));
                 },
                 IR::GTE => {
-                    self.output.push(0xF100_0000);
+                    self.output.push(opcode::encode(opcode::GTE, 0));
                 },
                 IR::EQ => {
-                    self.output.push(0xF200_0000);
+                    self.output.push(opcode::encode(opcode::EQ, 0));
                 },
                 IR::NEQ => {
-                    self.output.push(0xF300_0000);
+                    self.output.push(opcode::encode(opcode::NEQ, 0));
                 },
                 IR::NOOP => {
-                    self.output.push(0xF400_0000);
+                    self.output.push(opcode::encode(opcode::NOOP, 0));
                 },
                 IR::StartFunc => {
-                    self.output.push(0xF400_0000);
+                    self.output.push(opcode::encode(opcode::NOOP, 0));
+                },
+                IR::ENTER(depth, size) => {
+                    let operand = opcode::frame_operand(depth, size);
+                    self.output.push(opcode::encode(opcode::ENTER, operand));
                 },
                 IR::RET => {
-                    self.output.push(0xF500_0000);
+                    self.output.push(opcode::encode(opcode::RET, 0));
                 },
                 IR::HALT => {
-                    self.output.push(0xF600_0000);
+                    self.output.push(opcode::encode(opcode::HALT, 0));
+                },
+                IR::READ => {
+                    self.output.push(opcode::encode(opcode::READ, 0));
                 },
                 IR::DEC(n) => {
-                    self.output.push(n.try_into().unwrap());
+                    self.output.push(n as u32);
                 },
             }
         }
+        Ok(())
+    }
+
+    fn symbol(&self, label: &Label) -> Result<u32, IrErrorKind> {
+        self.symbol_table.get(label)
+            .copied()
+            .ok_or_else(|| IrErrorKind::UndefinedLabel(label.clone()))
+    }
+
+    /*
+     * Number of words an instruction occupies. Constants that fit in a
+     * sign-extended 24 bit immediate are encoded inline; anything wider
+     * is a LOADW followed by the full 32 bit value.
+     */
+    fn size(inst: &IR) -> u32 {
+        match inst {
+            IR::LOADC(n) if !(-0x80_0000..0x80_0000).contains(n) => 2,
+            _ => 1,
+        }
     }
 }
@@ -0,0 +1,450 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+
+use crate::codegen::{CodeGen, LineTable};
+use crate::disasm::{self, Labels};
+use crate::io::{Io, StdIo};
+use crate::ir::Label;
+use crate::vm::{ExitStatus, VmError, VM};
+
+/*
+ * Why execution paused.
+ */
+enum Stop {
+    Step,
+    Breakpoint,
+    Watch { address: u32, old: i32, new: i32 },
+    Finished(Result<ExitStatus, VmError>),
+}
+
+/*
+ * An interactive session over a VM running a compiled program. The
+ * program starts paused at its first instruction and only runs when told
+ * to step or continue. Data cells can be named by the global variable
+ * they hold, and code by the source line it was generated from.
+ */
+pub struct Debugger<I: Io = StdIo> {
+    vm: VM<I>,
+    image: Vec<u32>,
+    code_len: usize,
+    labels: Labels,
+    line_table: LineTable,
+    // Global variables and the address of their data cell.
+    variables: Vec<(String, u32)>,
+    breakpoints: BTreeSet<u32>,
+    // Watched data cells and the value they had when last checked.
+    watchpoints: BTreeMap<u32, i32>,
+    finished: bool,
+}
+
+impl Debugger {
+    pub fn new(codegen: &CodeGen, globals: &[(String, Label)]) -> Debugger {
+        Debugger::with_io(StdIo::new(), codegen, globals)
+    }
+
+    /*
+     * Read commands from stdin until it is closed or the user quits. An
+     * empty line repeats the previous command.
+     */
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut previous = String::new();
+        self.show_pc();
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(n) if n > 0 => (),
+                _ => {
+                    println!();
+                    break;
+                }
+            }
+
+            let mut command = line.trim().to_string();
+            if command.is_empty() {
+                command = previous.clone();
+            }
+            if !self.command(&command) {
+                break;
+            }
+            previous = command;
+        }
+    }
+}
+
+impl<I: Io> Debugger<I> {
+    /*
+     * Load the program generated by `codegen` into a VM doing its `!` and
+     * `?` through `io`. `globals` are the global variables reported by
+     * `IRGen` with the labels of their cells.
+     */
+    pub fn with_io(io: I, codegen: &CodeGen, globals: &[(String, Label)]) -> Debugger<I> {
+        let object = codegen.object();
+        let mut vm = VM::with_io(io);
+        vm.load_object(&object);
+
+        let variables = globals.iter()
+            .filter_map(|(name, label)| {
+                codegen.symbol_table.get(label).map(|address| (name.clone(), *address))
+            })
+            .collect();
+
+        Debugger {
+            vm,
+            image: codegen.output.clone(),
+            code_len: codegen.code_len as usize,
+            labels: disasm::labels(&object.symbols),
+            line_table: codegen.line_table.clone(),
+            variables,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            finished: false,
+        }
+    }
+
+    /*
+     * Run one command, returning false when the session should end.
+     */
+    fn command(&mut self, command: &str) -> bool {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            [] => (),
+            ["step" | "s"] => self.resume(Some(1)),
+            ["step" | "s", n] => match n.parse() {
+                Ok(n) => self.resume(Some(n)),
+                Err(_) => eprintln!("invalid count '{}'", n),
+            },
+            ["continue" | "c"] => self.resume(None),
+            ["break" | "b", location] => self.add_breakpoint(location),
+            ["watch" | "w", cell] => self.add_watchpoint(cell),
+            ["delete" | "d"] => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+            },
+            ["stack"] => println!("{:?}", self.vm.stack()),
+            ["rstack"] => {
+                let addresses: Vec<String> = self.vm.return_stack().iter()
+                    .map(|address| format!("{:04x}", address))
+                    .collect();
+                println!("[{}]", addresses.join(", "));
+            },
+            ["pc"] => self.show_pc(),
+            ["mem" | "x", cell] => self.show_memory(cell, "1"),
+            ["mem" | "x", cell, len] => self.show_memory(cell, len),
+            ["info" | "i"] => self.show_info(),
+            ["quit" | "q"] => return false,
+            ["help" | "h"] => {
+                println!("step, s [n]        execute n instructions");
+                println!("continue, c        run to a breakpoint, watchpoint or the end");
+                println!("break, b <line>    stop before code from a source line");
+                println!("break, b *<addr>   stop before the instruction at a hex address");
+                println!("watch, w <var>     stop when a global variable changes");
+                println!("watch, w *<addr>   stop when a data cell changes");
+                println!("delete, d          remove all breakpoints and watchpoints");
+                println!("stack              show the operand stack, top last");
+                println!("rstack             show the return stack, innermost last");
+                println!("pc                 show the next instruction");
+                println!("mem, x <cell> [n]  show n memory cells from a variable or *<addr>");
+                println!("info, i            list breakpoints, watchpoints and variables");
+                println!("quit, q            leave the debugger");
+            },
+            _ => eprintln!("unknown command '{}', try help", command),
+        }
+        true
+    }
+
+    /*
+     * Execute `count` instructions, or until something stops the program
+     * when there is no count.
+     */
+    fn resume(&mut self, count: Option<usize>) {
+        if self.finished {
+            println!("the program is not running");
+            return;
+        }
+
+        let mut executed = 0;
+        let stop = loop {
+            let status = match self.vm.step() {
+                Ok(status) => status,
+                Err(err) => break Stop::Finished(Err(err)),
+            };
+            if let Some(status) = status {
+                break Stop::Finished(Ok(status));
+            }
+            executed += 1;
+
+            if let Some(stop) = self.check_watchpoints() {
+                break stop;
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                break Stop::Breakpoint;
+            }
+            if count.is_some_and(|count| executed >= count) {
+                break Stop::Step;
+            }
+        };
+
+        match stop {
+            Stop::Step => (),
+            Stop::Breakpoint => println!("breakpoint at {:04x}", self.vm.pc()),
+            Stop::Watch { address, old, new } => {
+                println!("{} changed from {} to {}", self.cell_name(address), old, new);
+            },
+            Stop::Finished(Ok(ExitStatus::Halted)) => {
+                println!("program halted");
+                self.finished = true;
+                return;
+            },
+            Stop::Finished(Err(err)) => {
+                match self.line_table.line(err.pc()) {
+                    Some(line) => println!("runtime error at line {}: {}", line, err.message()),
+                    None => println!("runtime error at {}", err),
+                }
+                self.finished = true;
+                return;
+            },
+        }
+        self.show_pc();
+    }
+
+    /*
+     * Report the first watched cell whose value changed, updating every
+     * watchpoint to the current value.
+     */
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        let memory = self.vm.memory();
+        let mut stop = None;
+        for (address, value) in self.watchpoints.iter_mut() {
+            let new = memory[*address as usize];
+            if new != *value {
+                if stop.is_none() {
+                    stop = Some(Stop::Watch { address: *address, old: *value, new });
+                }
+                *value = new;
+            }
+        }
+        stop
+    }
+
+    fn add_breakpoint(&mut self, location: &str) {
+        if location.starts_with('*') {
+            match self.address(location) {
+                Some(address) if (address as usize) < self.code_len => {
+                    self.breakpoints.insert(address);
+                    println!("breakpoint at {:04x}", address);
+                },
+                Some(address) => eprintln!("{:04x} is not an instruction", address),
+                None => eprintln!("invalid address '{}'", location),
+            }
+            return;
+        }
+
+        let line = match location.parse() {
+            Ok(line) => line,
+            Err(_) => {
+                eprintln!("invalid line '{}'", location);
+                return;
+            }
+        };
+        let addresses = self.line_table.addresses(line);
+        if addresses.is_empty() {
+            eprintln!("no code for line {}", line);
+            return;
+        }
+        for address in addresses.iter() {
+            self.breakpoints.insert(*address);
+            println!("breakpoint at {:04x}, line {}", address, line);
+        }
+    }
+
+    fn add_watchpoint(&mut self, cell: &str) {
+        match self.address(cell) {
+            Some(address) if self.is_data(address) => {
+                let value = self.vm.memory()[address as usize];
+                self.watchpoints.insert(address, value);
+                println!("watching {} = {}", self.cell_name(address), value);
+            },
+            Some(address) => eprintln!("{:04x} is not a data cell", address),
+            None => eprintln!("unknown variable or address '{}'", cell),
+        }
+    }
+
+    fn show_pc(&self) {
+        let pc = self.vm.pc();
+        if pc as usize >= self.code_len {
+            println!("{:04x}  (not an instruction)", pc);
+            return;
+        }
+
+        let (text, _) = disasm::decode(&self.image, pc as usize, &self.labels);
+        match self.line_table.line(pc) {
+            Some(line) => println!("{:04x}  {:<24} line {}", pc, text, line),
+            None => println!("{:04x}  {}", pc, text),
+        }
+    }
+
+    fn show_memory(&self, cell: &str, len: &str) {
+        let (address, len) = match (self.address(cell), len.parse::<usize>()) {
+            (Some(address), Ok(len)) => (address as usize, len),
+            (None, _) => {
+                eprintln!("unknown variable or address '{}'", cell);
+                return;
+            },
+            (_, Err(_)) => {
+                eprintln!("invalid count '{}'", len);
+                return;
+            },
+        };
+
+        let cells = self.vm.memory().iter().enumerate().skip(address).take(len);
+        for (address, value) in cells {
+            println!("{:04x}  {:<12} {}", address, self.cell_name(address as u32), value);
+        }
+    }
+
+    fn show_info(&self) {
+        for address in self.breakpoints.iter() {
+            match self.line_table.line(*address) {
+                Some(line) => println!("breakpoint {:04x}, line {}", address, line),
+                None => println!("breakpoint {:04x}", address),
+            }
+        }
+        for (address, value) in self.watchpoints.iter() {
+            println!("watchpoint {} = {}", self.cell_name(*address), value);
+        }
+        for (name, address) in self.variables.iter() {
+            println!("variable {} at {:04x}", name, address);
+        }
+    }
+
+    /*
+     * Resolve "*<hex address>" or the name of a global variable.
+     */
+    fn address(&self, cell: &str) -> Option<u32> {
+        match cell.strip_prefix('*') {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => self.variables.iter()
+                .find(|(name, _)| name == cell)
+                .map(|(_, address)| *address),
+        }
+    }
+
+    fn is_data(&self, address: u32) -> bool {
+        (self.code_len..self.image.len()).contains(&(address as usize))
+    }
+
+    /*
+     * A cell's variable name if it holds one, otherwise its address.
+     */
+    fn cell_name(&self, address: u32) -> String {
+        match self.variables.iter().find(|(_, a)| *a == address) {
+            Some((name, _)) => name.clone(),
+            None => format!("*{:04x}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::ScriptedIo;
+    use crate::irgen::IRGen;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    const PROGRAM: &str = "var x, y;
+begin
+    x := 1;
+    ? y;
+    x := x + y;
+    ! x
+end.";
+
+    fn debugger(input: &[i32]) -> Debugger<ScriptedIo> {
+        let tokens = Scanner::new(PROGRAM.chars().collect()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        Resolver::new().resolve(&program).unwrap();
+        let mut irgen = IRGen::new();
+        irgen.gen(program);
+        let mut codegen = CodeGen::new();
+        codegen.gen(&mut irgen.code).unwrap();
+        Debugger::with_io(ScriptedIo::new(input), &codegen, &irgen.globals)
+    }
+
+    #[test]
+    fn addresses_and_variables() {
+        let debugger = debugger(&[]);
+        let x = debugger.address("x").unwrap();
+        assert!(debugger.is_data(x));
+        assert_eq!(debugger.cell_name(x), "x");
+        assert_eq!(debugger.address("*1f"), Some(0x1f));
+        assert_eq!(debugger.address("*zz"), None);
+        assert_eq!(debugger.address("*"), None);
+        assert_eq!(debugger.address("z"), None);
+    }
+
+    #[test]
+    fn breakpoints_on_addresses_and_lines() {
+        let mut debugger = debugger(&[5]);
+        debugger.add_breakpoint("*0");
+        debugger.add_breakpoint(&format!("*{:x}", debugger.code_len));
+        debugger.add_breakpoint("*zz");
+        debugger.add_breakpoint("three");
+        debugger.add_breakpoint("100");
+        assert_eq!(debugger.breakpoints.iter().copied().collect::<Vec<u32>>(), [0]);
+
+        debugger.add_breakpoint("5");
+        let line = debugger.line_table.addresses(5);
+        assert!(!line.is_empty());
+        assert!(line.iter().all(|address| debugger.breakpoints.contains(address)));
+
+        debugger.resume(None);
+        assert!(line.contains(&debugger.vm.pc()));
+        assert!(!debugger.finished);
+    }
+
+    #[test]
+    fn watchpoints_only_on_data() {
+        let mut debugger = debugger(&[]);
+        debugger.add_watchpoint("*0");
+        debugger.add_watchpoint("z");
+        assert!(debugger.watchpoints.is_empty());
+
+        debugger.add_watchpoint("y");
+        let y = debugger.address("y").unwrap();
+        assert_eq!(debugger.watchpoints.get(&y), Some(&0));
+    }
+
+    #[test]
+    fn continue_stops_when_a_watched_cell_changes() {
+        let mut debugger = debugger(&[5]);
+        debugger.add_watchpoint("x");
+        let x = debugger.address("x").unwrap();
+
+        debugger.resume(None);
+        assert_eq!(debugger.vm.memory()[x as usize], 1);
+        assert_eq!(debugger.watchpoints.get(&x), Some(&1));
+        assert!(!debugger.finished);
+
+        debugger.resume(None);
+        assert_eq!(debugger.watchpoints.get(&x), Some(&6));
+        assert!(debugger.vm.io().output().is_empty());
+
+        debugger.resume(None);
+        assert!(debugger.finished);
+        assert_eq!(debugger.vm.io().output(), [6]);
+    }
+
+    #[test]
+    fn step_counts_instructions() {
+        let mut debugger = debugger(&[]);
+        assert!(debugger.command("step 2"));
+        assert!(debugger.vm.pc() > 0);
+        assert!(!debugger.command("quit"));
+    }
+}
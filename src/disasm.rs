@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::codegen::Object;
+use crate::opcode::{self, Operand};
+
+/*
+ * Decode a program image into a listing. Words before `code_len` are
+ * instructions and the rest are data cells. Symbols are printed as
+ * labels above the address they name and next to operands referring to
+ * them.
+ *
+ *   l4:
+ *   0002  3000003c      LOAD    003c (sym0)
+ *   0003  4000000a      LOADC   10
+ */
+pub fn disassemble<W: Write>(w: &mut W,
+                             words: &[u32],
+                             code_len: usize,
+                             symbols: &[(String, u32)]) -> io::Result<()> {
+    let labels = labels(symbols);
+
+    let mut pc = 0;
+    while pc < words.len() {
+        let address = pc as u32;
+        let cell = words[pc];
+
+        if let Some(names) = labels.get(&address) {
+            for name in names.iter() {
+                writeln!(w, "{}:", name)?;
+            }
+        }
+
+        if pc >= code_len {
+            writeln!(w, "{:04x}  {:08x}      DEC     {}", address, cell, cell as i32)?;
+            pc += 1;
+            continue;
+        }
+
+        let (text, len) = decode(words, pc, &labels);
+        writeln!(w, "{:04x}  {:08x}      {}", address, cell, text)?;
+        pc += len;
+    }
+
+    Ok(())
+}
+
+/*
+ * Symbol names by the address they name.
+ */
+pub type Labels = HashMap<u32, Vec<String>>;
+
+pub fn labels(symbols: &[(String, u32)]) -> Labels {
+    let mut labels: Labels = HashMap::new();
+    for (name, address) in symbols.iter() {
+        labels.entry(*address).or_default().push(name.clone());
+    }
+    labels
+}
+
+/*
+ * Decode the instruction at `pc` into its mnemonic and operand, returning
+ * the text and the number of words the instruction takes up.
+ */
+pub fn decode(words: &[u32], pc: usize, labels: &Labels) -> (String, usize) {
+    let cell = words[pc];
+    let (mnemonic, operand) = match opcode::lookup(opcode::opcode(cell)) {
+        Some(op) => op,
+        None => return ("???".to_string(), 1),
+    };
+
+    let mut len = 1;
+    let operand = match operand {
+        Operand::None => String::new(),
+        Operand::Address => {
+            let target = opcode::address(cell);
+            match labels.get(&target) {
+                Some(names) => format!("{:04x} ({})", target, names[0]),
+                None => format!("{:04x}", target),
+            }
+        },
+        Operand::Immediate => opcode::immediate(cell).to_string(),
+        Operand::Wide => match words.get(pc + 1) {
+            Some(value) => {
+                len += 1;
+                (*value as i32).to_string()
+            },
+            None => "???".to_string(),
+        },
+        Operand::Frame => {
+            let (depth, offset) = opcode::frame(cell);
+            format!("{},{}", depth, offset)
+        },
+    };
+    let text = format!("{:<7} {}", mnemonic, operand);
+    (text.trim_end().to_string(), len)
+}
+
+/*
+ * Disassemble an object file with its data loaded after the code, as the
+ * VM sees it.
+ */
+pub fn disassemble_object<W: Write>(w: &mut W, object: &Object) -> io::Result<()> {
+    let words: Vec<u32> = object.code.iter()
+        .chain(object.data.iter())
+        .copied()
+        .collect();
+    disassemble(w, &words, object.code.len(), &object.symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(words: &[u32], code_len: usize, symbols: &[(String, u32)]) -> String {
+        let mut out = Vec::new();
+        disassemble(&mut out, words, code_len, symbols).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn wide_constants_take_two_words() {
+        let words = [opcode::encode(opcode::LOADW, 0), 2_000_000_000, opcode::encode(opcode::HALT, 0)];
+        assert_eq!(decode(&words, 0, &Labels::new()), ("LOADW   2000000000".to_string(), 2));
+        assert_eq!(decode(&words, 2, &Labels::new()), ("HALT".to_string(), 1));
+    }
+
+    #[test]
+    fn listing_skips_the_value_of_a_wide_constant() {
+        let words = [opcode::encode(opcode::LOADW, 0), 2_000_000_000, opcode::encode(opcode::WRITE, 0)];
+        assert_eq!(listing(&words, 3, &[]),
+                   "0000  41000000      LOADW   2000000000\n\
+                    0002  70000000      WRITE\n");
+    }
+
+    #[test]
+    fn truncated_wide_constant() {
+        let words = [opcode::encode(opcode::LOADW, 0)];
+        assert_eq!(decode(&words, 0, &Labels::new()), ("LOADW   ???".to_string(), 1));
+    }
+
+    #[test]
+    fn address_operands_name_their_label() {
+        let labels = labels(&[("loop".to_string(), 0x1f), ("x".to_string(), 0x40)]);
+        let words = [opcode::encode(opcode::JMP, 0x1f),
+                     opcode::encode(opcode::STORE, 0x40),
+                     opcode::encode(opcode::CALL, 0x123)];
+        assert_eq!(decode(&words, 0, &labels).0, "JMP     001f (loop)");
+        assert_eq!(decode(&words, 1, &labels).0, "STORE   0040 (x)");
+        assert_eq!(decode(&words, 2, &labels).0, "CALL    0123");
+    }
+
+    #[test]
+    fn immediate_and_frame_operands() {
+        let words = [opcode::encode(opcode::LOADC, (-3i32) as u32),
+                     opcode::encode(opcode::LOADL, opcode::frame_operand(2, 7)),
+                     opcode::encode(opcode::ENTER, opcode::frame_operand(1, 3))];
+        assert_eq!(decode(&words, 0, &Labels::new()).0, "LOADC   -3");
+        assert_eq!(decode(&words, 1, &Labels::new()).0, "LOADL   2,7");
+        assert_eq!(decode(&words, 2, &Labels::new()).0, "ENTER   1,3");
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let words = [0x0100_0000];
+        assert_eq!(decode(&words, 0, &Labels::new()), ("???".to_string(), 1));
+    }
+
+    #[test]
+    fn data_after_code_len() {
+        let words = [opcode::encode(opcode::LOAD, 2),
+                     opcode::encode(opcode::HALT, 0),
+                     0xffff_fff9,
+                     opcode::encode(opcode::HALT, 0)];
+        let symbols = [("x".to_string(), 2), ("y".to_string(), 3)];
+        assert_eq!(listing(&words, 2, &symbols),
+                   "0000  30000002      LOAD    0002 (x)\n\
+                    0001  f6000000      HALT\n\
+                    x:\n\
+                    0002  fffffff9      DEC     -7\n\
+                    y:\n\
+                    0003  f6000000      DEC     -167772160\n");
+    }
+}
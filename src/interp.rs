@@ -1,221 +1,529 @@
 use std::collections::HashMap;
-use std::ops::Neg;
-use std::process;
-use crate::ast::{Block, Expr, Literal, Type};
+use std::fmt;
+use crate::ast::{Block, Expr, Literal, Span, Spanned, Type};
+use crate::io::{InputError, Io, StdIo};
 
 #[derive(Clone, Debug)]
 pub enum EnvVal {
     Number(i32),
-    ProcVal(Block),
+    ProcVal(Vec<String>, Spanned<Block>),
+    FuncVal(Vec<String>, Spanned<Block>),
 }
 
-#[derive(Default)]
-pub struct Interp {
-    pub env: HashMap<String, EnvVal>
+// Name of the slot holding a function's result in its frame. It cannot
+// clash with a PL/0 identifier.
+const RESULT: &str = "$result";
+
+/*
+ * Calls may nest this deep before evaluation fails rather than overflow
+ * the stack. The VM enforces the same limit so both backends agree. Each
+ * call takes several nested `eval`s, so a thread running the
+ * interpreter needs STACK_SIZE bytes of stack to reach the limit.
+ */
+pub const MAX_CALL_DEPTH: usize = 1000;
+pub const STACK_SIZE: usize = 256 << 20;
+
+/*
+ * One activation of a block. `parent` is the frame of the lexically
+ * enclosing block, which is where lookups continue when a name is not
+ * declared locally.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    pub vars: HashMap<String, EnvVal>,
+    pub parent: Option<usize>,
+}
+
+/*
+ * The chain of frames making up the interpreter's environment. The last
+ * frame is always the one currently executing; frame 0 holds the
+ * program's global declarations.
+ */
+#[derive(Clone, Debug)]
+pub struct Env {
+    frames: Vec<Frame>,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env {
+            frames: vec![Frame::default()]
+        }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /*
+     * Declare a name in the current frame, shadowing any outer binding.
+     */
+    pub fn define(&mut self, name: String, val: EnvVal) {
+        self.frames.last_mut().unwrap().vars.insert(name, val);
+    }
+
+    /*
+     * Find a name by walking the static chain, returning the index of the
+     * frame that declares it along with its value.
+     */
+    pub fn lookup(&self, name: &str) -> Option<(usize, &EnvVal)> {
+        let mut frame = Some(self.frames.len() - 1);
+        while let Some(i) = frame {
+            if let Some(val) = self.frames[i].vars.get(name) {
+                return Some((i, val));
+            }
+            frame = self.frames[i].parent;
+        }
+
+        None
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EnvVal> {
+        self.lookup(name).map(|(_, val)| val)
+    }
+
+    /*
+     * Update an existing binding, returning false if the name is not
+     * visible from the current frame.
+     */
+    pub fn assign(&mut self, name: &str, val: EnvVal) -> bool {
+        match self.lookup(name) {
+            Some((i, _)) => {
+                self.frames[i].vars.insert(name.to_string(), val);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn push(&mut self, parent: usize) {
+        self.frames.push(Frame {
+            vars: HashMap::new(),
+            parent: Some(parent),
+        });
+    }
+
+    pub fn pop(&mut self) {
+        self.frames.pop();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeErrorKind {
+    Undeclared(String),
+    NotAProcedure(String),
+    NotAFunction(String),
+    DivisionByZero,
+    EndOfInput,
+    InvalidInput(String),
+    BudgetExhausted,
+    CallTooDeep,
+}
+
+/*
+ * An error raised while evaluating a program. `trace` lists the active
+ * procedure calls, innermost first, with the span of each call site.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub span: Span,
+    pub kind: RuntimeErrorKind,
+    pub trace: Vec<(String, Span)>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            RuntimeErrorKind::Undeclared(name) =>
+                write!(f, "{}: '{}' is not declared", self.span, name),
+            RuntimeErrorKind::NotAProcedure(name) =>
+                write!(f, "{}: '{}' is not a procedure", self.span, name),
+            RuntimeErrorKind::NotAFunction(name) =>
+                write!(f, "{}: '{}' is not a function", self.span, name),
+            RuntimeErrorKind::DivisionByZero =>
+                write!(f, "{}: division by zero", self.span),
+            RuntimeErrorKind::EndOfInput =>
+                write!(f, "{}: unexpected end of input", self.span),
+            RuntimeErrorKind::InvalidInput(text) =>
+                write!(f, "{}: expected a number, got '{}'", self.span, text),
+            RuntimeErrorKind::BudgetExhausted =>
+                write!(f, "{}: step budget exhausted", self.span),
+            RuntimeErrorKind::CallTooDeep =>
+                write!(f, "{}: calls nested more than {} deep", self.span, MAX_CALL_DEPTH),
+        }
+    }
+}
+
+// Calls listed under a runtime error before the rest are summarized.
+pub const MAX_CALLS_SHOWN: usize = 10;
+
+/*
+ * Print the calls in progress when a runtime error happened to stderr,
+ * innermost first. Runaway recursion leaves thousands of them, so only
+ * the innermost few are listed.
+ */
+pub fn print_calls(calls: impl ExactSizeIterator<Item = String>) {
+    let hidden = calls.len().saturating_sub(MAX_CALLS_SHOWN);
+    for call in calls.take(MAX_CALLS_SHOWN) {
+        eprintln!("    {}", call);
+    }
+    if hidden > 0 {
+        eprintln!("    ... {} more", hidden);
+    }
+}
+
+pub struct Interp<I: Io = StdIo> {
+    pub env: Env,
+    io: I,
+    // Set by `return` to unwind statements up to the enclosing call.
+    returning: bool,
+    // Procedures being executed and the spans they were called from.
+    calls: Vec<(String, Span)>,
+    // Statements left to run before giving up, if limited.
+    budget: Option<u64>,
+}
+
+impl Default for Interp {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interp {
     pub fn new() -> Interp {
+        Interp::with_io(StdIo::new())
+    }
+}
+
+impl<I: Io> Interp<I> {
+    /*
+     * Create an interpreter that does its `!` and `?` through `io`.
+     */
+    pub fn with_io(io: I) -> Interp<I> {
         Interp {
-            env: HashMap::new()
+            env: Env::new(),
+            io,
+            returning: false,
+            calls: Vec::new(),
+            budget: None,
         }
     }
 
-    pub fn eval(&mut self, program: Block) {
-        match program {
-            Block::Program(p) => self.eval(*p),
+    pub fn io(&self) -> &I {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    /*
+     * Limit the number of statements later evaluation may run, so a
+     * program that never ends fails instead. Every node `eval` visits
+     * counts as one.
+     */
+    pub fn set_budget(&mut self, steps: Option<u64>) {
+        self.budget = steps;
+    }
+
+    pub fn eval(&mut self, program: Spanned<Block>) -> Result<(), RuntimeError> {
+        if let Some(budget) = self.budget.as_mut() {
+            if *budget == 0 {
+                return Err(self.error(program.span, RuntimeErrorKind::BudgetExhausted));
+            }
+            *budget -= 1;
+        }
+
+        match program.node {
+            Block::Program(p) => self.eval(*p)?,
             Block::Block(consts, vars, procs, stmts) => {
                 self.extend_env_consts(*consts);
                 self.extend_env_vars(*vars);
                 self.extend_env_procs(procs);
-                self.eval(*stmts);
+                self.eval(*stmts)?;
             },
             Block::Begin(stmts) => {
                 for stmt in stmts {
-                    self.eval(stmt);
+                    self.eval(stmt)?;
+                    if self.returning {
+                        break;
+                    }
                 }
             },
-            Block::If(expr, block) => {
-                let val = self.eval_expr(expr);
-                if val > 0 {
-                    self.eval(*block);
+            Block::If(expr, block, alternative) => {
+                let val = self.eval_expr(expr)?;
+                if val != 0 {
+                    self.eval(*block)?;
+                } else if let Some(alternative) = alternative {
+                    self.eval(*alternative)?;
                 }
             },
             Block::Assign(var, expr) => {
-                let val = self.eval_expr(expr);
-                if let Expr::Var(s) = var {
-                    self.env.insert(s, EnvVal::Number(val));
-                }
+                let val = self.eval_expr(expr)?;
+                self.store(var, val)?;
             },
             Block::WriteLn(expr) => {
-                println!("{}", self.eval_expr(expr));
+                let val = self.eval_expr(expr)?;
+                self.io.write_int(val);
+            },
+            Block::Read(var) => {
+                let val = self.read_number(program.span)?;
+                self.store(var, val)?;
             },
             Block::While(expr, stmt) => {
                 loop {
-                    let val = self.eval_expr(expr.clone());
-                    if val < 1 {
+                    let val = self.eval_expr(expr.clone())?;
+                    if val == 0 {
                         break;
                     }
 
-                    self.eval(*stmt.clone());
+                    self.eval(*stmt.clone())?;
+                    if self.returning {
+                        break;
+                    }
                 }
             },
-            Block::Call(expr) => {
-                if let Expr::Var(v) = expr {
-                    let procval = self.env.get(&v);
-                    if procval.is_none() {
-                        eprintln!("function {} not defined", v);
-                        process::exit(1);
-                    }
-                    let procval = procval.unwrap().clone();
-                    if let EnvVal::ProcVal(p) = procval {
-                        self.eval(p.to_owned());
-                    } else {
-                        eprintln!("{} is not a function", v);
-                        process::exit(1);
-                    }
+            Block::Call(Spanned { node: Expr::Var(v), span }, args) => {
+                let (frame, procval) = self.env.lookup(&v)
+                    .ok_or_else(|| self.error(span, RuntimeErrorKind::Undeclared(v.clone())))?;
+                if let EnvVal::ProcVal(..) = procval {
+                    let procval = procval.clone();
+                    let args = self.eval_args(args)?;
+                    self.invoke(&v, span, frame, procval, args)?;
+                } else {
+                    return Err(self.error(span, RuntimeErrorKind::NotAProcedure(v)));
                 }
             },
+            Block::Return(expr) => {
+                let val = self.eval_expr(expr)?;
+                self.assign(RESULT, val, program.span)?;
+                self.returning = true;
+            },
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Add const, var, procedure or function declarations to the current
+     * frame, as if they had been declared at the top of its block.
+     */
+    pub fn declare(&mut self, decl: Spanned<Block>) {
+        match decl.node {
+            Block::ConstDecs(_) => self.extend_env_consts(decl),
+            Block::VarDecs(_) => self.extend_env_vars(decl),
+            Block::Procedure(..) | Block::Function(..) => self.extend_env_procs(vec![decl]),
             _ => (),
         }
     }
 
-    fn eval_expr(&mut self, expr: Expr) -> i32 {
-        match expr {
+    /*
+     * Arguments are evaluated in the caller's scope before the callee's
+     * frame exists.
+     */
+    fn eval_args(&mut self, args: Vec<Spanned<Expr>>) -> Result<Vec<i32>, RuntimeError> {
+        args.into_iter()
+            .map(|arg| self.eval_expr(arg))
+            .collect()
+    }
+
+    /*
+     * Run a procedure or function body. Locals live in a fresh frame whose
+     * parent is the frame the procedure was declared in. Returns the
+     * function's result, or 0 for a procedure. The frame is popped even
+     * when the body fails, so the interpreter can keep being used.
+     */
+    fn invoke(&mut self,
+              name: &str,
+              span: Span,
+              frame: usize,
+              callee: EnvVal,
+              args: Vec<i32>) -> Result<i32, RuntimeError> {
+        let (params, body, function) = match callee {
+            EnvVal::ProcVal(params, body) => (params, body, false),
+            EnvVal::FuncVal(params, body) => (params, body, true),
+            EnvVal::Number(_) => return Ok(0),
+        };
+
+        if self.calls.len() >= MAX_CALL_DEPTH {
+            return Err(self.error(span, RuntimeErrorKind::CallTooDeep));
+        }
+
+        self.env.push(frame);
+        self.calls.push((name.to_string(), span));
+        for (param, arg) in params.into_iter().zip(args) {
+            self.env.define(param, EnvVal::Number(arg));
+        }
+        if function {
+            self.env.define(RESULT.to_string(), EnvVal::Number(0));
+        }
+
+        let status = self.eval(body);
+        self.returning = false;
+
+        let result = match self.env.get(RESULT) {
+            Some(EnvVal::Number(n)) if function => *n,
+            _ => 0,
+        };
+        self.calls.pop();
+        self.env.pop();
+        status.map(|_| result)
+    }
+
+    pub fn eval_expr(&mut self, expr: Spanned<Expr>) -> Result<i32, RuntimeError> {
+        let span = expr.span;
+        let val = match expr.node {
             Expr::Literal(l) => {
                 let Literal::Number(n) = l;
                 n
             },
             Expr::Var(v) => {
-                let val = self.env.get(&v);
-                if val.is_none() {
-                    eprintln!("variable {} not declared", v);
-                    process::exit(1);
+                match self.env.get(&v) {
+                    Some(EnvVal::Number(n)) => *n,
+                    Some(_) => 0,
+                    None => return Err(self.error(span, RuntimeErrorKind::Undeclared(v))),
                 }
-
-                if let EnvVal::Number(n) = val.unwrap() {
-                    return *n;
+            },
+            Expr::Call(v, args) => {
+                let (frame, funcval) = self.env.lookup(&v)
+                    .ok_or_else(|| self.error(span, RuntimeErrorKind::Undeclared(v.clone())))?;
+                if let EnvVal::FuncVal(..) = funcval {
+                    let funcval = funcval.clone();
+                    let args = self.eval_args(args)?;
+                    self.invoke(&v, span, frame, funcval, args)?
                 } else {
-                    return 0;
+                    return Err(self.error(span, RuntimeErrorKind::NotAFunction(v)));
                 }
             },
-            Expr::PrefixExpr(prefix, expr) => {
-                if prefix.is_some() {
-                    let prefix = prefix.unwrap();
-                    match prefix {
-                        Type::Minus => self.eval_expr(*expr).neg(),
-                        Type::Plus => self.eval_expr(*expr),
-                        _ => self.eval_expr(*expr),
-                    }
-                } else {
-                    self.eval_expr(*expr)
+            Expr::PrefixExpr(prefix, e) => {
+                match prefix {
+                    Some(Type::Minus) => self.eval_expr(*e)?.wrapping_neg(),
+                    _ => self.eval_expr(*e)?,
                 }
             },
             Expr::Expr(left, sign, right) => {
+                let left = self.eval_expr(*left)?;
+                let right = self.eval_expr(*right)?;
                 match sign {
-                    Type::Plus => self.eval_expr(*left) + self.eval_expr(*right),
-                    Type::Minus => self.eval_expr(*left) - self.eval_expr(*right),
-                    Type::Star => self.eval_expr(*left) * self.eval_expr(*right),
-                    Type::Slash => self.eval_expr(*left) / self.eval_expr(*right),
-                    Type::Greater => {
-                        let left = self.eval_expr(*left);
-                        let right = self.eval_expr(*right);
-                        if left > right {
-                            1
-                        } else {
-                            0
-                        }
-                    },
-                    Type::GreaterEqual => {
-                        let left = self.eval_expr(*left);
-                        let right = self.eval_expr(*right);
-                        if left >= right {
-                            1
-                        } else {
-                            0
-                        }
-                    },
-                    Type::Less => {
-                        let left = self.eval_expr(*left);
-                        let right = self.eval_expr(*right);
-                        if left < right {
-                            1
-                        } else {
-                            0
-                        }
-                    },
-                    Type::LessEqual => {
-                        let left = self.eval_expr(*left);
-                        let right = self.eval_expr(*right);
-                        if left <= right {
-                            1
-                        } else {
-                            0
-                        }
-                    },
-                    Type::Equal => {
-                        let left = self.eval_expr(*left);
-                        let right = self.eval_expr(*right);
-                        if left == right {
-                            1
-                        } else {
-                            0
-                        }
-                    },
-                    Type::Hash => {
-                        let left = self.eval_expr(*left);
-                        let right = self.eval_expr(*right);
-                        if left != right {
-                            1
-                        } else {
-                            0
+                    Type::Plus => left.wrapping_add(right),
+                    Type::Minus => left.wrapping_sub(right),
+                    Type::Star => left.wrapping_mul(right),
+                    Type::Slash => {
+                        if right == 0 {
+                            return Err(self.error(span, RuntimeErrorKind::DivisionByZero));
                         }
+                        left.wrapping_div(right)
                     },
+                    Type::Greater => (left > right) as i32,
+                    Type::GreaterEqual => (left >= right) as i32,
+                    Type::Less => (left < right) as i32,
+                    Type::LessEqual => (left <= right) as i32,
+                    Type::Equal => (left == right) as i32,
+                    Type::Hash => (left != right) as i32,
                     _ => 0
                 }
             },
-            Expr::OddExpr(expr) => {
-                let val = self.eval_expr(*expr);
-                if val % 2 == 1 {
-                    1
-                } else {
-                    0
-                }
+            Expr::OddExpr(e) => {
+                let val = self.eval_expr(*e)?;
+                (val % 2 != 0) as i32
             },
-            Expr::Group(expr) => self.eval_expr(*expr),
+            Expr::Group(e) => self.eval_expr(*e)?,
+        };
+
+        Ok(val)
+    }
+
+    /*
+     * Store into the target of an assignment or read. Naming the
+     * enclosing function sets its result.
+     */
+    fn store(&mut self, var: Spanned<Expr>, val: i32) -> Result<(), RuntimeError> {
+        if let Expr::Var(name) = var.node {
+            if let Some(EnvVal::FuncVal(..)) = self.env.get(&name) {
+                self.assign(RESULT, val, var.span)?;
+            } else {
+                self.assign(&name, val, var.span)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, name: &str, val: i32, span: Span) -> Result<(), RuntimeError> {
+        if self.env.assign(name, EnvVal::Number(val)) {
+            Ok(())
+        } else {
+            Err(self.error(span, RuntimeErrorKind::Undeclared(name.to_string())))
         }
     }
 
-    fn extend_env_consts(&mut self, block: Block) {
-        if let Block::ConstDecs(cds) = block {
+    fn read_number(&mut self, span: Span) -> Result<i32, RuntimeError> {
+        self.io.read_int().map_err(|err| match err {
+            InputError::EndOfInput => self.error(span, RuntimeErrorKind::EndOfInput),
+            InputError::Invalid(text) =>
+                self.error(span, RuntimeErrorKind::InvalidInput(text)),
+        })
+    }
+
+    fn error(&self, span: Span, kind: RuntimeErrorKind) -> RuntimeError {
+        RuntimeError {
+            span,
+            kind,
+            trace: self.calls.iter().rev().cloned().collect(),
+        }
+    }
+
+    fn extend_env_consts(&mut self, block: Spanned<Block>) {
+        if let Block::ConstDecs(cds) = block.node {
             for cd in cds {
-                if let Block::Const(Expr::Var(s),
-                                    Expr::Literal(l)) = cd {
-                    let Literal::Number(n) = l;
-                    self.env.insert(s, EnvVal::Number(n));
+                if let Block::Const(name, value) = cd.node {
+                    if let (Expr::Var(s), Expr::Literal(l)) = (name.node, value.node) {
+                        let Literal::Number(n) = l;
+                        self.env.define(s, EnvVal::Number(n));
+                    }
                 }
             }
         }
     }
 
-    fn extend_env_vars(&mut self, block: Block) {
-        if let Block::VarDecs(vds) = block {
+    fn extend_env_vars(&mut self, block: Spanned<Block>) {
+        if let Block::VarDecs(vds) = block.node {
             for v in vds {
-                if let Expr::Var(s) = v {
-                    self.env.insert(s, EnvVal::Number(0));
+                if let Expr::Var(s) = v.node {
+                    self.env.define(s, EnvVal::Number(0));
                 }
             }
         }
     }
 
-    fn extend_env_procs(&mut self, block: Vec<Block>) {
+    fn extend_env_procs(&mut self, block: Vec<Spanned<Block>>) {
         for b in block {
-            if let Block::Procedure(name, body) = b {
-                if let Expr::Var(v) = name {
-                    let procval = EnvVal::ProcVal(*body);
-                    self.env.insert(v, procval);
-                }
+            let (name, procval) = match b.node {
+                Block::Procedure(name, params, body) =>
+                    (name, EnvVal::ProcVal(Self::param_names(params), *body)),
+                Block::Function(name, params, body) =>
+                    (name, EnvVal::FuncVal(Self::param_names(params), *body)),
+                _ => continue,
+            };
+
+            if let Expr::Var(v) = name.node {
+                self.env.define(v, procval);
             }
         }
     }
+
+    fn param_names(params: Vec<Spanned<Expr>>) -> Vec<String> {
+        params.into_iter()
+            .filter_map(|p| match p.node {
+                Expr::Var(s) => Some(s),
+                _ => None,
+            })
+            .collect()
+    }
 }
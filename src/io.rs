@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Cursor};
+
+/*
+ * Why reading a number failed.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputError {
+    EndOfInput,
+    Invalid(String),
+}
+
+/*
+ * Where a running program's `!` output goes and its `?` input comes from.
+ * Both backends are generic over it, so the same program can talk to the
+ * terminal or to a test.
+ */
+pub trait Io {
+    fn write_int(&mut self, n: i32);
+    fn read_int(&mut self) -> Result<i32, InputError>;
+
+    // Free-form text such as prompts. Ignored unless the implementation
+    // has somewhere to put it.
+    fn write_str(&mut self, _s: &str) {}
+}
+
+/*
+ * Read one number per line.
+ */
+fn read_line_int<R: BufRead>(input: &mut R) -> Result<i32, InputError> {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(n) if n > 0 => (),
+        _ => return Err(InputError::EndOfInput),
+    }
+
+    line.trim().parse().map_err(|_| InputError::Invalid(line.trim().to_string()))
+}
+
+/*
+ * The process's standard input and output. Stdin is only locked for the
+ * duration of a read, so others such as the REPL can share it.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdIo;
+
+impl StdIo {
+    pub fn new() -> StdIo {
+        StdIo
+    }
+}
+
+impl Io for StdIo {
+    fn write_int(&mut self, n: i32) {
+        println!("{}", n);
+    }
+
+    fn read_int(&mut self) -> Result<i32, InputError> {
+        read_line_int(&mut io::stdin().lock())
+    }
+
+    fn write_str(&mut self, s: &str) {
+        print!("{}", s);
+    }
+}
+
+/*
+ * Reads lines from a string and collects everything written, formatted
+ * exactly as `StdIo` would print it.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct BufferIo {
+    input: Cursor<Vec<u8>>,
+    output: String,
+}
+
+impl BufferIo {
+    pub fn new(input: &str) -> BufferIo {
+        BufferIo {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: String::new(),
+        }
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+}
+
+impl Io for BufferIo {
+    fn write_int(&mut self, n: i32) {
+        self.output.push_str(&format!("{}\n", n));
+    }
+
+    fn read_int(&mut self) -> Result<i32, InputError> {
+        read_line_int(&mut self.input)
+    }
+
+    fn write_str(&mut self, s: &str) {
+        self.output.push_str(s);
+    }
+}
+
+/*
+ * Answers reads from a fixed list of numbers and records the numbers
+ * written.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedIo {
+    input: VecDeque<i32>,
+    output: Vec<i32>,
+}
+
+impl ScriptedIo {
+    pub fn new(input: &[i32]) -> ScriptedIo {
+        ScriptedIo {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> &[i32] {
+        &self.output
+    }
+}
+
+impl Io for ScriptedIo {
+    fn write_int(&mut self, n: i32) {
+        self.output.push(n);
+    }
+
+    fn read_int(&mut self) -> Result<i32, InputError> {
+        self.input.pop_front().ok_or(InputError::EndOfInput)
+    }
+}
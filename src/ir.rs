@@ -1,3 +1,9 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::ast::Span;
+use crate::opcode;
+
 pub type Label = String;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -6,9 +12,12 @@ pub enum IR {
     JMZ(Label),
     LOAD(Label),
     LOADC(i32),
+    LOADL(u32, u32),
     STORE(Label),
+    STOREL(u32, u32),
     CALL(Label),
     WRITE,
+    READ,
     ADD,
     SUB,
     DIV,
@@ -22,19 +31,479 @@ pub enum IR {
     NEQ,
     NOOP,
     StartFunc,
+    ENTER(u32, u32),
     RET,
     HALT,
     DEC(i32),
 }
 
-#[derive(Clone, Debug)]
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IR::JMP(l) => write!(f, "JMP {}", l),
+            IR::JMZ(l) => write!(f, "JMZ {}", l),
+            IR::LOAD(l) => write!(f, "LOAD {}", l),
+            IR::LOADC(n) => write!(f, "LOADC {}", n),
+            IR::LOADL(depth, offset) => write!(f, "LOADL {},{}", depth, offset),
+            IR::STORE(l) => write!(f, "STORE {}", l),
+            IR::STOREL(depth, offset) => write!(f, "STOREL {},{}", depth, offset),
+            IR::CALL(l) => write!(f, "CALL {}", l),
+            IR::WRITE => write!(f, "WRITE"),
+            IR::READ => write!(f, "READ"),
+            IR::ADD => write!(f, "ADD"),
+            IR::SUB => write!(f, "SUB"),
+            IR::DIV => write!(f, "DIV"),
+            IR::MUL => write!(f, "MUL"),
+            IR::ODD => write!(f, "ODD"),
+            IR::LT => write!(f, "LT"),
+            IR::LTE => write!(f, "LTE"),
+            IR::GT => write!(f, "GT"),
+            IR::GTE => write!(f, "GTE"),
+            IR::EQ => write!(f, "EQ"),
+            IR::NEQ => write!(f, "NEQ"),
+            IR::NOOP => write!(f, "NOOP"),
+            IR::StartFunc => write!(f, "FUNC"),
+            IR::ENTER(depth, size) => write!(f, "ENTER {},{}", depth, size),
+            IR::RET => write!(f, "RET"),
+            IR::HALT => write!(f, "HALT"),
+            IR::DEC(n) => write!(f, "DEC {}", n),
+        }
+    }
+}
+
+/*
+ * An instruction with an optional label. `span` is the source construct
+ * the instruction was generated for, if there is one.
+ */
+#[derive(Clone, Debug, PartialEq)]
 pub struct Line {
     pub label: Option<Label>,
-    pub inst: IR
+    pub inst: IR,
+    pub span: Option<Span>,
 }
 
 impl Line {
     pub fn new(label: Option<Label>, inst: IR) -> Line {
-        Line { label, inst }
+        Line { label, inst, span: None }
+    }
+}
+
+/*
+ * One line per instruction, with its label in a column of its own:
+ *
+ *   l4:     LOAD sym0
+ *           JMZ l5
+ */
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{:<7} {}", format!("{}:", label), self.inst),
+            None => write!(f, "        {}", self.inst),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum IrErrorKind {
+    UnknownMnemonic(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    MissingOperand(String),
+    UnexpectedOperand(String),
+    BadOperand(String),
+    UnclosedFunc,
+}
+
+/*
+ * An error in IR text, located by its 1-based line number.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrError {
+    pub line: usize,
+    pub kind: IrErrorKind,
+}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            IrErrorKind::UnknownMnemonic(m) =>
+                write!(f, "{}: unknown mnemonic '{}'", self.line, m),
+            IrErrorKind::UndefinedLabel(l) =>
+                write!(f, "{}: label '{}' is not defined", self.line, l),
+            IrErrorKind::DuplicateLabel(l) =>
+                write!(f, "{}: label '{}' is already defined", self.line, l),
+            IrErrorKind::MissingOperand(m) =>
+                write!(f, "{}: '{}' expects an operand", self.line, m),
+            IrErrorKind::UnexpectedOperand(m) =>
+                write!(f, "{}: '{}' takes no operand", self.line, m),
+            IrErrorKind::BadOperand(o) =>
+                write!(f, "{}: invalid operand '{}'", self.line, o),
+            IrErrorKind::UnclosedFunc =>
+                write!(f, "{}: FUNC has no matching RET", self.line),
+        }
+    }
+}
+
+/*
+ * Parse IR text as written by the `Display` implementation of `Line`:
+ * one instruction per line, each optionally preceded by a label. A label
+ * on a line of its own names the next instruction. Everything after a
+ * ';' is a comment. Labels must be defined exactly once and every FUNC
+ * must be closed by a RET, so the result can be handed straight to
+ * `CodeGen`.
+ */
+pub fn parse(source: &str) -> Result<Vec<Line>, Vec<IrError>> {
+    let mut errors = Vec::new();
+    let mut lines = Vec::new();
+    // Source line numbers of `lines`, for reporting label errors.
+    let mut numbers = Vec::new();
+    let mut pending: Option<Label> = None;
+
+    for (i, text) in source.lines().enumerate() {
+        let number = i + 1;
+        match parse_line(text) {
+            Ok(None) => (),
+            Ok(Some(SourceLine { label, inst: None })) => {
+                // Two labels in a row need a NOOP to hold the first.
+                if let Some(first) = pending.take() {
+                    lines.push(Line::new(Some(first), IR::NOOP));
+                    numbers.push(number);
+                }
+                pending = label;
+            },
+            Ok(Some(SourceLine { label, inst: Some(inst) })) => {
+                if let Some(first) = pending.take() {
+                    match label {
+                        Some(_) => {
+                            lines.push(Line::new(Some(first), IR::NOOP));
+                            numbers.push(number);
+                            lines.push(Line::new(label, inst));
+                        },
+                        None => lines.push(Line::new(Some(first), inst)),
+                    }
+                } else {
+                    lines.push(Line::new(label, inst));
+                }
+                numbers.push(number);
+            },
+            Err(kind) => errors.push(IrError { line: number, kind }),
+        }
+    }
+    if let Some(label) = pending {
+        lines.push(Line::new(Some(label), IR::NOOP));
+        numbers.push(source.lines().count());
+    }
+
+    let mut defined = HashSet::new();
+    for (line, number) in lines.iter().zip(numbers.iter()) {
+        if let Some(label) = &line.label {
+            if !defined.insert(label.clone()) {
+                errors.push(IrError {
+                    line: *number,
+                    kind: IrErrorKind::DuplicateLabel(label.clone()),
+                });
+            }
+        }
+    }
+    for (line, number) in lines.iter().zip(numbers.iter()) {
+        match &line.inst {
+            IR::JMP(l) | IR::JMZ(l) | IR::LOAD(l) | IR::STORE(l) | IR::CALL(l)
+                if !defined.contains(l) => {
+                errors.push(IrError {
+                    line: *number,
+                    kind: IrErrorKind::UndefinedLabel(l.clone()),
+                });
+            },
+            _ => (),
+        }
+    }
+
+    // A RET closes the innermost open FUNC. One outside any FUNC is an
+    // ordinary instruction.
+    let mut open = Vec::new();
+    for (line, number) in lines.iter().zip(numbers.iter()) {
+        match line.inst {
+            IR::StartFunc => open.push(*number),
+            IR::RET => {
+                open.pop();
+            },
+            _ => (),
+        }
+    }
+    for number in open {
+        errors.push(IrError { line: number, kind: IrErrorKind::UnclosedFunc });
+    }
+
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        errors.sort_by_key(|e| e.line);
+        Err(errors)
+    }
+}
+
+/*
+ * A source line before labels are attached: either part may be missing.
+ */
+struct SourceLine {
+    label: Option<Label>,
+    inst: Option<IR>,
+}
+
+fn parse_line(text: &str) -> Result<Option<SourceLine>, IrErrorKind> {
+    let text = match text.find(';') {
+        Some(i) => &text[..i],
+        None => text,
+    };
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return Ok(None);
+    }
+
+    let mut label = None;
+    if let Some(i) = rest.find(':') {
+        let name = rest[..i].trim();
+        if !is_label(name) {
+            return Err(IrErrorKind::BadOperand(name.to_string()));
+        }
+        label = Some(name.to_string());
+        rest = rest[i + 1..].trim();
+    }
+    if rest.is_empty() {
+        return Ok(Some(SourceLine { label, inst: None }));
+    }
+
+    let (mnemonic, operand) = match rest.find(char::is_whitespace) {
+        Some(i) => (&rest[..i], Some(rest[i..].trim())),
+        None => (rest, None),
+    };
+    let inst = parse_inst(mnemonic, operand)?;
+    Ok(Some(SourceLine { label, inst: Some(inst) }))
+}
+
+fn parse_inst(mnemonic: &str, operand: Option<&str>) -> Result<IR, IrErrorKind> {
+    let name = mnemonic.to_ascii_uppercase();
+    let simple = match name.as_str() {
+        "WRITE" => Some(IR::WRITE),
+        "READ" => Some(IR::READ),
+        "ADD" => Some(IR::ADD),
+        "SUB" => Some(IR::SUB),
+        "DIV" => Some(IR::DIV),
+        "MUL" => Some(IR::MUL),
+        "ODD" => Some(IR::ODD),
+        "LT" => Some(IR::LT),
+        "LTE" => Some(IR::LTE),
+        "GT" => Some(IR::GT),
+        "GTE" => Some(IR::GTE),
+        "EQ" => Some(IR::EQ),
+        "NEQ" => Some(IR::NEQ),
+        "NOOP" => Some(IR::NOOP),
+        "FUNC" => Some(IR::StartFunc),
+        "RET" => Some(IR::RET),
+        "HALT" => Some(IR::HALT),
+        _ => None,
+    };
+    if let Some(inst) = simple {
+        return match operand {
+            None => Ok(inst),
+            Some(_) => Err(IrErrorKind::UnexpectedOperand(name)),
+        };
+    }
+
+    let make: fn(&str) -> Result<IR, IrErrorKind> = match name.as_str() {
+        "JMP" => |o| label(o).map(IR::JMP),
+        "JMZ" => |o| label(o).map(IR::JMZ),
+        "LOAD" => |o| label(o).map(IR::LOAD),
+        "STORE" => |o| label(o).map(IR::STORE),
+        "CALL" => |o| label(o).map(IR::CALL),
+        "LOADC" => |o| number(o).map(IR::LOADC),
+        "DEC" => |o| number(o).map(IR::DEC),
+        "LOADL" => |o| frame(o).map(|(d, n)| IR::LOADL(d, n)),
+        "STOREL" => |o| frame(o).map(|(d, n)| IR::STOREL(d, n)),
+        "ENTER" => |o| frame(o).map(|(d, n)| IR::ENTER(d, n)),
+        _ => return Err(IrErrorKind::UnknownMnemonic(mnemonic.to_string())),
+    };
+    match operand {
+        Some(o) => make(o),
+        None => Err(IrErrorKind::MissingOperand(name)),
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn label(operand: &str) -> Result<Label, IrErrorKind> {
+    if is_label(operand) {
+        Ok(operand.to_string())
+    } else {
+        Err(IrErrorKind::BadOperand(operand.to_string()))
+    }
+}
+
+fn number(operand: &str) -> Result<i32, IrErrorKind> {
+    operand.parse().map_err(|_| IrErrorKind::BadOperand(operand.to_string()))
+}
+
+/*
+ * A frame operand written as "depth,offset". Both must fit in their
+ * fields of the encoded instruction.
+ */
+fn frame(operand: &str) -> Result<(u32, u32), IrErrorKind> {
+    let bad = || IrErrorKind::BadOperand(operand.to_string());
+    let mut parts = operand.split(',');
+    let depth = parts.next().and_then(|p| p.trim().parse().ok()).ok_or_else(bad)?;
+    let offset = parts.next().and_then(|p| p.trim().parse().ok()).ok_or_else(bad)?;
+    if parts.next().is_some() || depth > opcode::MAX_DEPTH || offset > opcode::MAX_OFFSET {
+        return Err(bad());
+    }
+    Ok((depth, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irgen::IRGen;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn lower(source: &str) -> Vec<Line> {
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        Resolver::new().resolve(&program).unwrap();
+        let mut irgen = IRGen::new();
+        irgen.gen(program);
+        irgen.code
+    }
+
+    fn display(lines: &[Line]) -> String {
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    fn errors(source: &str) -> Vec<(usize, IrErrorKind)> {
+        parse(source).unwrap_err().into_iter().map(|err| (err.line, err.kind)).collect()
+    }
+
+    #[test]
+    fn compiled_programs_round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
+        let mut paths: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "pas"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let source = std::fs::read_to_string(&path).unwrap();
+            let lines: Vec<Line> = lower(&source).into_iter()
+                .map(|line| Line { span: None, ..line })
+                .collect();
+            assert_eq!(parse(&display(&lines)).unwrap(), lines, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        let label = || Some("l0".to_string());
+        let lines = vec![
+            Line::new(label(), IR::JMP("l0".to_string())),
+            Line::new(None, IR::JMZ("l0".to_string())),
+            Line::new(None, IR::LOAD("l0".to_string())),
+            Line::new(None, IR::LOADC(-2147483648)),
+            Line::new(None, IR::LOADL(255, 65535)),
+            Line::new(None, IR::STORE("l0".to_string())),
+            Line::new(None, IR::STOREL(0, 0)),
+            Line::new(None, IR::CALL("l0".to_string())),
+            Line::new(None, IR::WRITE),
+            Line::new(None, IR::READ),
+            Line::new(None, IR::ADD),
+            Line::new(None, IR::SUB),
+            Line::new(None, IR::DIV),
+            Line::new(None, IR::MUL),
+            Line::new(None, IR::ODD),
+            Line::new(None, IR::LT),
+            Line::new(None, IR::LTE),
+            Line::new(None, IR::GT),
+            Line::new(None, IR::GTE),
+            Line::new(None, IR::EQ),
+            Line::new(None, IR::NEQ),
+            Line::new(None, IR::NOOP),
+            Line::new(None, IR::StartFunc),
+            Line::new(None, IR::ENTER(1, 3)),
+            Line::new(None, IR::RET),
+            Line::new(None, IR::HALT),
+            Line::new(None, IR::DEC(7)),
+        ];
+        assert_eq!(parse(&display(&lines)).unwrap(), lines);
+    }
+
+    #[test]
+    fn unknown_mnemonic() {
+        assert_eq!(errors("LOADC 1\nPUSH 2"), vec![(2, IrErrorKind::UnknownMnemonic("PUSH".to_string()))]);
+    }
+
+    #[test]
+    fn undefined_label() {
+        assert_eq!(errors("JMP end\nHALT"), vec![(1, IrErrorKind::UndefinedLabel("end".to_string()))]);
+    }
+
+    #[test]
+    fn duplicate_label() {
+        assert_eq!(errors("a: NOOP\na: HALT"), vec![(2, IrErrorKind::DuplicateLabel("a".to_string()))]);
+    }
+
+    #[test]
+    fn missing_operand() {
+        assert_eq!(errors("LOADC"), vec![(1, IrErrorKind::MissingOperand("LOADC".to_string()))]);
+    }
+
+    #[test]
+    fn unexpected_operand() {
+        assert_eq!(errors("HALT 1"), vec![(1, IrErrorKind::UnexpectedOperand("HALT".to_string()))]);
+    }
+
+    #[test]
+    fn bad_operand() {
+        let bad = |o: &str| IrErrorKind::BadOperand(o.to_string());
+        assert_eq!(errors("LOADC x"), vec![(1, bad("x"))]);
+        assert_eq!(errors("JMP 12"), vec![(1, bad("12"))]);
+        assert_eq!(errors("1a: HALT"), vec![(1, bad("1a"))]);
+        assert_eq!(errors("LOADL 1"), vec![(1, bad("1"))]);
+        assert_eq!(errors("LOADL 1,2,3"), vec![(1, bad("1,2,3"))]);
+    }
+
+    #[test]
+    fn frame_operands_must_fit_their_fields() {
+        let bad = |o: &str| IrErrorKind::BadOperand(o.to_string());
+        assert_eq!(errors("LOADL 256,0"), vec![(1, bad("256,0"))]);
+        assert_eq!(errors("STOREL 1,65536"), vec![(1, bad("1,65536"))]);
+        assert_eq!(errors("ENTER 300,0"), vec![(1, bad("300,0"))]);
+        assert!(parse("LOADL 255,65535").is_ok());
+    }
+
+    #[test]
+    fn unclosed_func() {
+        assert_eq!(errors("f: FUNC\nRET\nFUNC\nHALT"), vec![(3, IrErrorKind::UnclosedFunc)]);
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let kinds: Vec<IrErrorKind> = errors("PUSH\nLOADC\nJMP x\nFUNC")
+            .into_iter()
+            .map(|(_, kind)| kind)
+            .collect();
+        assert_eq!(kinds, vec![
+            IrErrorKind::UnknownMnemonic("PUSH".to_string()),
+            IrErrorKind::MissingOperand("LOADC".to_string()),
+            IrErrorKind::UndefinedLabel("x".to_string()),
+            IrErrorKind::UnclosedFunc,
+        ]);
     }
 }
@@ -1,24 +1,107 @@
 use std::collections::HashMap;
-use crate::ast::{Block, Expr, Literal, Type};
+use crate::ast::{Block, Expr, Literal, Span, Spanned, Type};
 use crate::ir::{IR, Label, Line};
 
+/*
+ * What a name declared in the program turns into. Variables of the main
+ * program are static data cells, while variables of a procedure live in
+ * its activation record, addressed by the static depth of the procedure
+ * and their offset in the frame.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Symbol {
+    Global(Label),
+    Local(u32, u32),
+    Const(i32),
+    Proc(Label),
+    Func(Label),
+}
+
+/*
+ * A function whose body is being generated. Its result lives in the last
+ * cell of its frame and is loaded onto the operand stack at `exit`, just
+ * before returning.
+ */
+#[derive(Clone, Debug)]
+struct Function {
+    name: String,
+    depth: u32,
+    result: u32,
+    exit: Label,
+}
+
 #[derive(Default)]
 pub struct IRGen {
-    pub symbol_table: HashMap<String, Label>,
-    pub const_table: HashMap<String, i32>,
+    pub scopes: Vec<HashMap<String, Symbol>>,
     pub code: Vec<Line>,
+    // Names of the program's global variables and their data labels.
+    pub globals: Vec<(String, Label)>,
+    // Names of the program's procedures and functions and their entry
+    // labels. Routines in different scopes may share a name.
+    pub routines: Vec<(String, Label)>,
     sym: u32,
     label: u32,
+    depth: u32,
+    // Number of parameter slots at the start of the current frame.
+    params: u32,
+    // Innermost function being generated, None inside a procedure.
+    functions: Vec<Option<Function>>,
 }
 
 impl IRGen {
     pub fn new() -> IRGen {
         IRGen {
-            symbol_table: HashMap::new(),
-            const_table: HashMap::new(),
+            scopes: Vec::new(),
             code: Vec::new(),
+            globals: Vec::new(),
+            routines: Vec::new(),
             sym: 0,
             label: 0,
+            depth: 0,
+            params: 0,
+            functions: Vec::new(),
+        }
+    }
+
+    fn declare(&mut self, name: String, symbol: Symbol) {
+        self.scopes.last_mut().unwrap().insert(name, symbol);
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.scopes.iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+    }
+
+    fn gen_load(&mut self, name: &str) {
+        match self.lookup(name) {
+            Some(Symbol::Global(s)) =>
+                self.code.push(Line::new(None, IR::LOAD(s))),
+            Some(Symbol::Local(depth, offset)) =>
+                self.code.push(Line::new(None, IR::LOADL(depth, offset))),
+            Some(Symbol::Const(n)) =>
+                self.code.push(Line::new(None, IR::LOADC(n))),
+            _ => (),
+        }
+    }
+
+    fn gen_store(&mut self, name: &str) {
+        if let Some(Symbol::Func(_)) = self.lookup(name) {
+            if let Some(Some(f)) = self.functions.last() {
+                if f.name == name {
+                    let inst = IR::STOREL(f.depth, f.result);
+                    self.code.push(Line::new(None, inst));
+                }
+            }
+            return;
+        }
+
+        match self.lookup(name) {
+            Some(Symbol::Global(s)) =>
+                self.code.push(Line::new(None, IR::STORE(s))),
+            Some(Symbol::Local(depth, offset)) =>
+                self.code.push(Line::new(None, IR::STOREL(depth, offset))),
+            _ => (),
         }
     }
 
@@ -34,44 +117,64 @@ impl IRGen {
         s
     }
 
-    pub fn gen(&mut self, program: Block) {
-        match program {
+    pub fn gen(&mut self, program: Spanned<Block>) {
+        let start = self.code.len();
+        let span = program.span;
+        let statement = matches!(program.node,
+            Block::Assign(..) | Block::Call(..) | Block::If(..) | Block::While(..) |
+            Block::WriteLn(_) | Block::Read(_) | Block::Return(_));
+
+        match program.node {
             Block::Program(p) => {
                 self.gen(*p);
                 self.code.push(Line::new(None, IR::HALT));
                 self.remove_noops();
             },
             Block::Block(consts, vars, procs, stmts) => {
+                self.scopes.push(HashMap::new());
                 self.gen_consts(*consts);
                 self.gen_vars(*vars);
                 self.gen_procs(procs);
                 self.gen(*stmts);
+                self.scopes.pop();
             },
             Block::Begin(stmts) => {
                 for stmt in stmts {
                     self.gen(stmt);
                 }
             },
-            Block::If(expr, block) => {
+            Block::If(expr, block, None) => {
                 let label = self.make_label();
                 self.gen_expr(expr);
                 self.code.push(Line::new(None, IR::JMZ(label.clone())));
                 self.gen(*block);
                 self.code.push(Line::new(Some(label.clone()), IR::NOOP));
             },
+            Block::If(expr, block, Some(alternative)) => {
+                let otherwise = self.make_label();
+                let end = self.make_label();
+                self.gen_expr(expr);
+                self.code.push(Line::new(None, IR::JMZ(otherwise.clone())));
+                self.gen(*block);
+                self.code.push(Line::new(None, IR::JMP(end.clone())));
+                self.code.push(Line::new(Some(otherwise), IR::NOOP));
+                self.gen(*alternative);
+                self.code.push(Line::new(Some(end), IR::NOOP));
+            },
             Block::Assign(var, expr) => {
                 self.gen_expr(expr);
-                if let Expr::Var(s) = var {
-                    let sym = self.symbol_table.get(&s);
-                    if let Some(s) = sym {
-                        self.code.push(Line::new(None, IR::STORE(s.to_string())))
-                    }
+                if let Expr::Var(s) = var.node {
+                    self.gen_store(&s);
                 }
             },
             Block::WriteLn(expr) => {
                 self.gen_expr(expr);
                 self.code.push(Line::new(None, IR::WRITE));
             },
+            Block::Read(Spanned { node: Expr::Var(v), .. }) => {
+                self.code.push(Line::new(None, IR::READ));
+                self.gen_store(&v);
+            },
             Block::While(expr, stmt) => {
                 let back = self.make_label();
                 let forward = self.make_label();
@@ -83,40 +186,63 @@ impl IRGen {
                 self.code.push(Line::new(None, IR::JMP(back.to_owned())));
                 self.code.push(Line::new(Some(forward.to_owned()), IR::NOOP));
             },
-            Block::Call(expr) => {
-                if let Expr::Var(v) = expr {
-                    let sym = self.symbol_table.get(&v);
-                    if let Some(s) = sym {
-                        self.code.push(
-                            Line::new(None, IR::CALL(s.to_string()))
-                        );
-                    }
+            Block::Call(Spanned { node: Expr::Var(v), .. }, args) => {
+                for arg in args {
+                    self.gen_expr(arg);
+                }
+
+                if let Some(Symbol::Proc(s)) = self.lookup(&v) {
+                    self.code.push(Line::new(None, IR::CALL(s)));
+                }
+            },
+            Block::Return(expr) => {
+                self.gen_expr(expr);
+                if let Some(Some(f)) = self.functions.last().cloned() {
+                    self.code.push(Line::new(None, IR::STOREL(f.depth, f.result)));
+                    self.code.push(Line::new(None, IR::JMP(f.exit)));
                 }
             },
             _ => (),
         }
+
+        if statement {
+            self.stamp(start, span);
+        }
     }
 
-    fn gen_expr(&mut self, expr: Expr) {
-        match expr {
+    /*
+     * Attribute the lines generated since `start` to `span`. Nested
+     * statements and subexpressions are generated first, so they keep
+     * their own spans.
+     */
+    fn stamp(&mut self, start: usize, span: Span) {
+        for line in self.code[start..].iter_mut() {
+            if line.span.is_none() {
+                line.span = Some(span);
+            }
+        }
+    }
+
+    fn gen_expr(&mut self, expr: Spanned<Expr>) {
+        let start = self.code.len();
+        let span = expr.span;
+        match expr.node {
             Expr::Literal(l) => {
                 let Literal::Number(n) = l;
                 self.code.push(Line::new(None, IR::LOADC(n)));
             },
-            Expr::Var(v) => {
-                let sym = self.symbol_table.get(&v);
-                if let Some(s) = sym {
-                    self.code.push(Line::new(None, IR::LOAD(s.to_string())));
-                } else {
-                    let sym = self.const_table.get(&v);
-                    if let Some(n) = sym {
-                        self.code.push(Line::new(None, IR::LOADC(*n)));
-                    }
+            Expr::Var(v) => self.gen_load(&v),
+            Expr::Call(v, args) => {
+                for arg in args {
+                    self.gen_expr(arg);
+                }
+
+                if let Some(Symbol::Func(s)) = self.lookup(&v) {
+                    self.code.push(Line::new(None, IR::CALL(s)));
                 }
             },
             Expr::PrefixExpr(prefix, expr) => {
-                if prefix.is_some() {
-                    let prefix = prefix.unwrap();
+                if let Some(prefix) = prefix {
                     match prefix {
                         Type::Minus => {
                             self.gen_expr(*expr);
@@ -195,58 +321,144 @@ impl IRGen {
             },
             Expr::Group(expr) => self.gen_expr(*expr),
         }
+        self.stamp(start, span);
     }
 
-    fn gen_consts(&mut self, block: Block) {
-        if let Block::ConstDecs(cds) = block {
+    fn gen_consts(&mut self, block: Spanned<Block>) {
+        if let Block::ConstDecs(cds) = block.node {
             for cd in cds {
-                if let Block::Const(Expr::Var(s),
-                                    Expr::Literal(l)) = cd {
-                    let Literal::Number(n) = l;
-                    self.const_table.insert(s, n);
+                if let Block::Const(name, value) = cd.node {
+                    if let (Expr::Var(s), Expr::Literal(l)) = (name.node, value.node) {
+                        let Literal::Number(n) = l;
+                        self.declare(s, Symbol::Const(n));
+                    }
                 }
             }
         }
     }
 
-    fn gen_vars(&mut self, block: Block) {
-        if let Block::VarDecs(vds) = block {
-            for v in vds {
-                if let Expr::Var(s) = v {
-                    let sym = self.make_symbol();
-                    self.symbol_table.insert(s, sym.clone());
-                    self.code.push(Line::new(Some(sym.clone()), IR::DEC(0)));
+    fn gen_vars(&mut self, block: Spanned<Block>) {
+        if let Block::VarDecs(vds) = block.node {
+            for (offset, v) in vds.into_iter().enumerate() {
+                if let Expr::Var(s) = v.node {
+                    if self.depth == 0 {
+                        let sym = self.make_symbol();
+                        self.globals.push((s.clone(), sym.clone()));
+                        self.declare(s, Symbol::Global(sym.clone()));
+                        self.code.push(Line::new(Some(sym), IR::DEC(0)));
+                    } else {
+                        let offset = self.params + offset as u32;
+                        self.declare(s, Symbol::Local(self.depth, offset));
+                    }
                 }
             }
         }
     }
 
-    fn gen_procs(&mut self, block: Vec<Block>) {
+    fn gen_procs(&mut self, block: Vec<Spanned<Block>>) {
         for b in block {
-            if let Block::Procedure(name, body) = b {
-                if let Expr::Var(v) = name {
-                    let sym = self.make_symbol();
-                    self.symbol_table.insert(v, sym.clone());
-                    self.code.push(Line::new(Some(sym.clone()), IR::StartFunc));
-                    self.gen(*body);
-                    self.code.push(Line::new(None, IR::RET));
+            match b.node {
+                Block::Procedure(name, params, body) =>
+                    self.gen_proc(name, params, *body, false),
+                Block::Function(name, params, body) =>
+                    self.gen_proc(name, params, *body, true),
+                _ => (),
+            }
+        }
+    }
+
+    /*
+     * Generate a procedure or function body. A function leaves its result
+     * on the operand stack when it returns.
+     */
+    fn gen_proc(&mut self,
+                name: Spanned<Expr>,
+                params: Vec<Spanned<Expr>>,
+                body: Spanned<Block>,
+                function: bool) {
+        let start = self.code.len();
+        if let Expr::Var(v) = name.node {
+            let sym = self.make_symbol();
+            self.routines.push((v.clone(), sym.clone()));
+            if function {
+                self.declare(v.clone(), Symbol::Func(sym.clone()));
+            } else {
+                self.declare(v.clone(), Symbol::Proc(sym.clone()));
+            }
+
+            // Every call gets its own activation record, so a
+            // recursive call cannot clobber the caller's locals.
+            self.depth += 1;
+            let outer_params = self.params;
+            self.params = params.len() as u32;
+            let mut size = self.params + IRGen::frame_size(&body);
+            if function {
+                let exit = self.make_label();
+                self.functions.push(Some(Function {
+                    name: v,
+                    depth: self.depth,
+                    result: size,
+                    exit,
+                }));
+                size += 1;
+            } else {
+                self.functions.push(None);
+            }
+            self.code.push(Line::new(Some(sym), IR::StartFunc));
+            self.code.push(Line::new(None, IR::ENTER(self.depth, size)));
+
+            // Arguments were pushed left to right by the caller, so
+            // they come off the operand stack in reverse order.
+            self.scopes.push(HashMap::new());
+            for (offset, p) in params.into_iter().enumerate().rev() {
+                if let Expr::Var(s) = p.node {
+                    let offset = offset as u32;
+                    self.declare(s, Symbol::Local(self.depth, offset));
+                    self.code.push(Line::new(None, IR::STOREL(self.depth, offset)));
                 }
             }
+
+            self.gen(body);
+            self.scopes.pop();
+
+            if let Some(Some(f)) = self.functions.pop() {
+                let inst = IR::LOADL(f.depth, f.result);
+                self.code.push(Line::new(Some(f.exit), inst));
+            }
+            self.code.push(Line::new(None, IR::RET));
+            self.params = outer_params;
+            self.depth -= 1;
+        }
+        // The prologue and epilogue belong to the declaration.
+        self.stamp(start, name.span);
+    }
+
+    /*
+     * Number of cells a procedure body needs in its activation record.
+     */
+    fn frame_size(body: &Spanned<Block>) -> u32 {
+        if let Block::Block(_, vars, _, _) = &body.node {
+            if let Block::VarDecs(vds) = &vars.node {
+                return vds.len() as u32;
+            }
         }
+
+        0
     }
 
     /*
      * Remove NOOPS that exist just to hold labels by passing the label forward.
+     * A NOOP is kept when the next line already carries a label of its own.
      */
     fn remove_noops(&mut self) {
         let mut i = 0;
         while i < self.code.len() {
             let current = self.code[i].clone();
-            if let Line { inst: IR::NOOP, label: l } = current {
-                if (i + 1) < self.code.len() {
-                    let mut next = &mut self.code[i + 1];
-                    next.label = l.clone();
+            if let Line { inst: IR::NOOP, label: l, .. } = current {
+                if (i + 1) < self.code.len() && self.code[i + 1].label.is_none() {
+                    self.code[i + 1].label = l;
                     self.code.remove(i);
+                    continue;
                 }
             }
 
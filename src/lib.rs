@@ -1,8 +1,16 @@
+pub mod asm;
 pub mod ast;
 pub mod codegen;
+pub mod debugger;
+pub mod disasm;
 pub mod interp;
+pub mod io;
 pub mod ir;
 pub mod irgen;
+pub mod opcode;
 pub mod parser;
+pub mod repl;
+pub mod resolver;
 pub mod scanner;
+pub mod trace;
 pub mod vm;
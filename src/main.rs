@@ -1,63 +1,431 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
 use std::env;
 use std::path::Path;
 use std::process;
+use std::thread;
 
 use lozenge::scanner::Scanner;
 use lozenge::parser::Parser;
-//use lozenge::interp::Interp;
+use lozenge::resolver::Resolver;
+use lozenge::ast::{Block, Spanned};
+use lozenge::interp::{self, Interp};
+use lozenge::ir::Line;
 use lozenge::irgen::IRGen;
-use lozenge::codegen::CodeGen;
-use lozenge::vm::VM;
+use lozenge::asm;
+use lozenge::codegen::{CodeGen, LineTable, Object};
+use lozenge::debugger::Debugger;
+use lozenge::disasm;
+use lozenge::repl::Repl;
+use lozenge::trace::{Filter, Tracer};
+use lozenge::vm::{self, VM};
+
+// Exit codes, following sysexits.h.
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_SOFTWARE: i32 = 70;
+const EX_OSERR: i32 = 71;
+const EX_CANTCREAT: i32 = 73;
+const EX_IOERR: i32 = 74;
+
+/*
+ * The engines a checked program can be run on.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    Interp,
+    Vm,
+}
+
+impl Backend {
+    fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "interp" => Some(Backend::Interp),
+            "vm" => Some(Backend::Vm),
+            _ => None,
+        }
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("usage: lozenge <file>");
-        process::exit(64);
+    match args.get(1).map(String::as_str) {
+        Some("run") => run(&args[2..]),
+        Some("repl") => match &args[2..] {
+            [] => with_interp_stack(|| Repl::new().run()),
+            _ => usage(),
+        },
+        Some("debug") => debug(one_file(&args[2..])),
+        Some("build") => build(&args[2..]),
+        Some("asm") => assemble(&args[2..]),
+        Some("exec") => exec(&args[2..]),
+        Some("ir") => print_ir(one_file(&args[2..])),
+        Some("disasm") => disasm(one_file(&args[2..])),
+        Some(file) if args.len() == 2 => execute(file, parse_file(file), Backend::Vm, None, None),
+        _ => usage(),
+    }
+}
+
+/*
+ * The only argument of a subcommand that takes a single file.
+ */
+fn one_file(args: &[String]) -> &str {
+    match args {
+        [file] => file,
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    println!("usage: lozenge <file>");
+    println!("       lozenge run [--backend=interp|vm] [--trace[=<from>-<to>|<procedure>]]");
+    println!("                   [--max-steps <n>] <file>");
+    println!("       lozenge repl");
+    println!("       lozenge debug <file>");
+    println!("       lozenge build <file> [-o <output>]");
+    println!("       lozenge asm <file> [-o <output>]");
+    println!("       lozenge exec [--max-steps <n>] <object>");
+    println!("       lozenge ir <file>");
+    println!("       lozenge disasm <file|object>");
+    process::exit(EX_USAGE);
+}
+
+fn run(args: &[String]) {
+    let mut backend = Backend::Vm;
+    let mut trace = None;
+    let mut budget = None;
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(steps) = max_steps(arg, &mut args) {
+            budget = Some(steps);
+            continue;
+        }
+
+        if arg == "--trace" {
+            trace = Some(Filter::All);
+            continue;
+        }
+        if let Some(spec) = arg.strip_prefix("--trace=") {
+            trace = Some(Filter::parse(spec).unwrap_or_else(|| {
+                eprintln!("invalid trace filter '{}'", spec);
+                usage()
+            }));
+            continue;
+        }
+
+        let name = if arg == "--backend" {
+            Some(args.next().unwrap_or_else(|| usage()).as_str())
+        } else {
+            arg.strip_prefix("--backend=")
+        };
+
+        match name {
+            Some(name) => {
+                backend = Backend::from_name(name).unwrap_or_else(|| {
+                    eprintln!("unknown backend '{}'", name);
+                    usage()
+                });
+            },
+            None if input.is_none() => input = Some(arg.clone()),
+            None => usage(),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    if trace.is_some() && backend != Backend::Vm {
+        eprintln!("--trace needs the vm backend");
+        usage();
+    }
+    let program = parse_file(&input);
+    execute(&input, program, backend, trace, budget);
+}
+
+/*
+ * Parse "--max-steps <n>" or "--max-steps=<n>", taking the count from
+ * `rest` when it is a separate argument. Returns None for any other
+ * argument.
+ */
+fn max_steps<'a>(arg: &'a str, rest: &mut impl Iterator<Item = &'a String>) -> Option<u64> {
+    let steps = if arg == "--max-steps" {
+        rest.next().unwrap_or_else(|| usage()).as_str()
     } else {
-        run_file(&args[1])
+        arg.strip_prefix("--max-steps=")?
+    };
+    Some(steps.parse().unwrap_or_else(|_| {
+        eprintln!("invalid step count '{}'", steps);
+        usage()
+    }))
+}
+
+/*
+ * Run a checked program, tracing the instructions `trace` selects on the
+ * VM and giving up after `budget` steps: statements on the interpreter,
+ * instructions on the VM. Runtime errors from either backend exit with
+ * EX_SOFTWARE.
+ */
+fn execute(name: &str,
+           program: Spanned<Block>,
+           backend: Backend,
+           trace: Option<Filter>,
+           budget: Option<u64>) {
+    match backend {
+        Backend::Interp => with_interp_stack(|| {
+            let mut interp = Interp::new();
+            interp.set_budget(budget);
+            if let Err(err) = interp.eval(program) {
+                eprintln!("{}:{}", name, err);
+                let calls = err.trace.iter()
+                    .map(|(procedure, span)| format!("in '{}' called at {}:{}", procedure, name, span));
+                interp::print_calls(calls);
+                process::exit(EX_SOFTWARE);
+            }
+        }),
+        Backend::Vm => {
+            let (irgen, codegen) = generate(program);
+            let mut vm = VM::new();
+            vm.load(&codegen.output);
+            vm.set_budget(budget);
+            if let Some(filter) = trace {
+                let tracer = Tracer::new(&codegen, &irgen.routines, &filter)
+                    .unwrap_or_else(|| {
+                        eprintln!("{}: no procedure or function named '{}'", name, filter);
+                        process::exit(EX_USAGE);
+                    });
+                vm.set_trace(Some(tracer.hook()));
+            }
+            run_vm(&mut vm, name, &codegen.line_table);
+        },
     }
 }
 
-fn run_file(file: &str) {
-    let path = Path::new(file);
-    let mut file = File::open(&path)
-        .expect("Failed to open file");
+fn debug(name: &str) {
+    let (irgen, codegen) = generate(parse_file(name));
+    Debugger::new(&codegen, &irgen.globals).run();
+}
 
-    let mut source = String::new();
-    file.read_to_string(&mut source)
-        .expect("Failed to read file");
+fn build(args: &[String]) {
+    let (input, output) = output_args(args);
+    let codegen = compile(parse_file(&input));
+    write_object(&codegen.object(), &output);
+}
 
-    let source: Vec<char> = source.chars().collect();
-    run(source);
+fn assemble(args: &[String]) {
+    let (input, output) = output_args(args);
+    let source = fs::read_to_string(&input).unwrap_or_else(|err| {
+        eprintln!("{}: {}", input, err);
+        process::exit(EX_NOINPUT);
+    });
+    let codegen = asm::assemble(&source).unwrap_or_else(|errs| {
+        for err in errs.iter() {
+            eprintln!("{}:{}", input, err);
+        }
+        process::exit(EX_DATAERR);
+    });
+    write_object(&codegen.object(), &output);
 }
 
-fn run(source: Vec<char>) {
-    let mut scanner = Scanner::new(source);
-    scanner.scan_tokens();
+/*
+ * Parse "<file> [-o <output>]", defaulting the output to the input file
+ * with a .lzb extension.
+ */
+fn output_args(args: &[String]) -> (String, String) {
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => usage(),
+        }
+    }
 
-    let mut parser = Parser::new(scanner.tokens);
-    let program = parser.parse().unwrap_or_else(|err| {
-        eprintln!("error: {}", err);
-        process::exit(1);
+    let input = input.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| {
+        Path::new(&input).with_extension("lzb").to_string_lossy().into_owned()
     });
+    (input, output)
+}
 
-    //let mut interp = Interp::new();
-    //interp.eval(program);
+fn write_object(object: &Object, output: &str) {
+    let file = File::create(output).unwrap_or_else(|err| {
+        eprintln!("{}: {}", output, err);
+        process::exit(EX_CANTCREAT);
+    });
+    let mut writer = BufWriter::new(file);
+    object.write(&mut writer)
+        .and_then(|_| writer.flush())
+        .unwrap_or_else(|err| {
+            eprintln!("{}: {}", output, err);
+            process::exit(EX_IOERR);
+        });
+}
+
+fn exec(args: &[String]) {
+    let mut budget = None;
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(steps) = max_steps(arg, &mut args) {
+            budget = Some(steps);
+        } else if input.is_none() {
+            input = Some(arg.as_str());
+        } else {
+            usage();
+        }
+    }
+    let name = input.unwrap_or_else(|| usage());
 
+    let file = File::open(name).unwrap_or_else(|err| {
+        eprintln!("{}: {}", name, err);
+        process::exit(EX_NOINPUT);
+    });
+    let object = vm::read_object(&mut BufReader::new(file)).unwrap_or_else(|err| {
+        eprintln!("{}: {}", name, err);
+        process::exit(EX_DATAERR);
+    });
+
+    let mut vm = VM::new();
+    vm.load_object(&object);
+    vm.set_budget(budget);
+    run_vm(&mut vm, name, &object.lines);
+}
+
+/*
+ * Run a loaded program. A runtime error is reported at its source line
+ * when `lines` knows it, followed by the calls in progress, innermost
+ * first.
+ */
+fn run_vm(vm: &mut VM, name: &str, lines: &LineTable) {
+    if let Err(err) = vm.run() {
+        match lines.line(err.pc()) {
+            Some(line) => eprintln!("runtime error at {}:{}: {}", name, line, err.message()),
+            None => eprintln!("{}: runtime error at {}", name, err),
+        }
+        // Return addresses point just past the CALL.
+        let calls = vm.return_stack().iter().rev().map(|address| {
+            let call = address.saturating_sub(1);
+            match lines.line(call) {
+                Some(line) => format!("called at {}:{}", name, line),
+                None => format!("called at {:04x}", call),
+            }
+        });
+        interp::print_calls(calls);
+        process::exit(EX_SOFTWARE);
+    }
+}
+
+/*
+ * Run `f` on a thread with enough stack for the interpreter to nest
+ * calls up to `interp::MAX_CALL_DEPTH`.
+ */
+fn with_interp_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .stack_size(interp::STACK_SIZE)
+            .spawn_scoped(scope, f)
+            .map(|handle| handle.join())
+            .unwrap_or_else(|err| {
+                eprintln!("cannot start the interpreter: {}", err);
+                process::exit(EX_OSERR);
+            })
+            .unwrap_or_else(|_| process::exit(EX_SOFTWARE))
+    })
+}
+
+fn print_ir(name: &str) {
+    for line in lower(parse_file(name)).iter() {
+        println!("{}", line);
+    }
+}
+
+/*
+ * List the bytecode of an object file, or of a source file compiled on
+ * the fly when the input is not an object file.
+ */
+fn disasm(name: &str) {
+    let file = File::open(name).unwrap_or_else(|err| {
+        eprintln!("{}: {}", name, err);
+        process::exit(EX_NOINPUT);
+    });
+    let object = match vm::read_object(&mut BufReader::new(file)) {
+        Ok(object) => object,
+        Err(vm::ObjectError::BadMagic) => compile(parse_file(name)).object(),
+        Err(err) => {
+            eprintln!("{}: {}", name, err);
+            process::exit(EX_DATAERR);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    disasm::disassemble_object(&mut out, &object)
+        .and_then(|_| out.flush())
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(EX_IOERR);
+        });
+}
+
+fn compile(program: Spanned<Block>) -> CodeGen {
+    generate(program).1
+}
+
+/*
+ * Compile a program, keeping the IR generator for what it learned about
+ * the program's names.
+ */
+fn generate(program: Spanned<Block>) -> (IRGen, CodeGen) {
     let mut irgen = IRGen::new();
     irgen.gen(program);
-    //for c in irgen.code.iter() {
-    //    println!("{:?}", c);
-    //}
-
-    let mut ir = irgen.code.clone();
     let mut codegen = CodeGen::new();
-    codegen.gen(&mut ir);
+    codegen.gen(&mut irgen.code).expect("IRGen emits well-formed IR");
+    (irgen, codegen)
+}
 
-    let mut vm = VM::new();
-    vm.load(&codegen.output);
-    vm.run();
+/*
+ * Scan, parse and resolve a source file, exiting with its errors if the
+ * program is not valid.
+ */
+fn parse_file(name: &str) -> Spanned<Block> {
+    let source = fs::read_to_string(name).unwrap_or_else(|err| {
+        eprintln!("{}: {}", name, err);
+        process::exit(EX_NOINPUT);
+    });
+    let source: Vec<char> = source.chars().collect();
+
+    let mut scanner = Scanner::new(source);
+    let tokens = scanner.scan_tokens().unwrap_or_else(|errs| {
+        for err in errs.iter() {
+            eprintln!("{}:{}", name, err);
+        }
+        process::exit(EX_DATAERR);
+    });
+
+    let mut parser = Parser::new(tokens);
+    let program = parser.parse().unwrap_or_else(|errs| {
+        for err in errs.iter() {
+            eprintln!("{}:{}", name, err);
+        }
+        process::exit(EX_DATAERR);
+    });
+
+    let mut resolver = Resolver::new();
+    resolver.resolve(&program).unwrap_or_else(|errs| {
+        for err in errs.iter() {
+            eprintln!("{}:{}", name, err);
+        }
+        process::exit(EX_DATAERR);
+    });
+
+    program
+}
+
+fn lower(program: Spanned<Block>) -> Vec<Line> {
+    let mut irgen = IRGen::new();
+    irgen.gen(program);
+    irgen.code
 }
@@ -0,0 +1,123 @@
+/*
+ * The VM instruction set. An instruction is a single word with the opcode
+ * in the top 8 bits and its operand in the low 24 bits. LOADW is the only
+ * instruction that takes a second word, holding a full 32 bit constant.
+ */
+pub const JMP: u32 = 0x10;
+pub const JMZ: u32 = 0x20;
+pub const LOAD: u32 = 0x30;
+pub const LOADL: u32 = 0x31;
+pub const LOADC: u32 = 0x40;
+pub const LOADW: u32 = 0x41;
+pub const STORE: u32 = 0x50;
+pub const STOREL: u32 = 0x51;
+pub const CALL: u32 = 0x60;
+pub const WRITE: u32 = 0x70;
+pub const ADD: u32 = 0x80;
+pub const SUB: u32 = 0x90;
+pub const DIV: u32 = 0xA0;
+pub const MUL: u32 = 0xB0;
+pub const ODD: u32 = 0xC0;
+pub const LT: u32 = 0xD0;
+pub const LTE: u32 = 0xE0;
+pub const GT: u32 = 0xF0;
+pub const GTE: u32 = 0xF1;
+pub const EQ: u32 = 0xF2;
+pub const NEQ: u32 = 0xF3;
+pub const NOOP: u32 = 0xF4;
+pub const RET: u32 = 0xF5;
+pub const HALT: u32 = 0xF6;
+pub const READ: u32 = 0xF7;
+pub const ENTER: u32 = 0xF8;
+
+/*
+ * How the low 24 bits of an instruction are interpreted.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    None,
+    // An absolute address in memory.
+    Address,
+    // A sign-extended 24 bit constant.
+    Immediate,
+    // The constant is held in the following word.
+    Wide,
+    // A static depth and a frame offset, see `frame_operand`.
+    Frame,
+}
+
+pub const TABLE: [(u32, &str, Operand); 26] = [
+    (JMP, "JMP", Operand::Address),
+    (JMZ, "JMZ", Operand::Address),
+    (LOAD, "LOAD", Operand::Address),
+    (LOADL, "LOADL", Operand::Frame),
+    (LOADC, "LOADC", Operand::Immediate),
+    (LOADW, "LOADW", Operand::Wide),
+    (STORE, "STORE", Operand::Address),
+    (STOREL, "STOREL", Operand::Frame),
+    (CALL, "CALL", Operand::Address),
+    (WRITE, "WRITE", Operand::None),
+    (ADD, "ADD", Operand::None),
+    (SUB, "SUB", Operand::None),
+    (DIV, "DIV", Operand::None),
+    (MUL, "MUL", Operand::None),
+    (ODD, "ODD", Operand::None),
+    (LT, "LT", Operand::None),
+    (LTE, "LTE", Operand::None),
+    (GT, "GT", Operand::None),
+    (GTE, "GTE", Operand::None),
+    (EQ, "EQ", Operand::None),
+    (NEQ, "NEQ", Operand::None),
+    (NOOP, "NOOP", Operand::None),
+    (RET, "RET", Operand::None),
+    (HALT, "HALT", Operand::None),
+    (READ, "READ", Operand::None),
+    (ENTER, "ENTER", Operand::Frame),
+];
+
+/*
+ * Look up the mnemonic and operand kind of an opcode.
+ */
+pub fn lookup(op: u32) -> Option<(&'static str, Operand)> {
+    TABLE.iter()
+        .find(|(code, _, _)| *code == op)
+        .map(|(_, name, operand)| (*name, *operand))
+}
+
+pub fn opcode(cell: u32) -> u32 {
+    (cell & 0xFF00_0000) >> 24
+}
+
+// Number of words a 24 bit address operand can reach.
+pub const ADDRESS_SPACE: u32 = 1 << 24;
+
+pub fn encode(op: u32, operand: u32) -> u32 {
+    (op << 24) | (operand & 0x00FF_FFFF)
+}
+
+pub fn address(cell: u32) -> u32 {
+    cell & 0x00FF_FFFF
+}
+
+pub fn immediate(cell: u32) -> i32 {
+    // Shift the 24 bit immediate to the top of the word and back down
+    // again to sign-extend it.
+    ((cell << 8) as i32) >> 8
+}
+
+/*
+ * Frame instructions pack the static depth into bits 16..24 and the
+ * offset (or frame size for ENTER) into the low 16 bits.
+ */
+pub const MAX_DEPTH: u32 = 0xFF;
+pub const MAX_OFFSET: u32 = 0xFFFF;
+
+pub fn frame_operand(depth: u32, offset: u32) -> u32 {
+    ((depth & 0xFF) << 16) | (offset & 0xFFFF)
+}
+
+pub fn frame(cell: u32) -> (usize, usize) {
+    let depth = (cell & 0x00FF_0000) >> 16;
+    let offset = cell & 0x0000_FFFF;
+    (depth as usize, offset as usize)
+}
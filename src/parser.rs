@@ -1,78 +1,234 @@
-use crate::ast::{Type, Token, Expr, Block};
+use std::fmt;
+
+use crate::ast::{Type, Token, Expr, Block, Span, Spanned};
+
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub span: Span,
+    pub expected: Vec<Type>,
+    pub found: Token,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)?;
+
+        if !self.expected.is_empty() {
+            let expected: Vec<String> = self.expected.iter()
+                .map(|t| format!("'{}'", t))
+                .collect();
+            write!(f, ", expected {}", expected.join(" or "))?;
+        }
+
+        if self.found.r#type == Type::EOF {
+            write!(f, ", found end of file")
+        } else {
+            write!(f, ", found '{}'", self.found.lexeme)
+        }
+    }
+}
+
+impl ParseError {
+    /*
+     * Whether the parser ran out of input, meaning more text could still
+     * complete it.
+     */
+    pub fn is_incomplete(&self) -> bool {
+        self.found.r#type == Type::EOF
+    }
+}
+
+/*
+ * One piece of interactive input: a run of declarations, a statement or
+ * a bare expression.
+ */
+#[derive(Clone, Debug)]
+pub enum Input {
+    Declaration(Spanned<Block>),
+    Statement(Spanned<Block>),
+    Expression(Spanned<Expr>),
+}
+
+// Errors are boxed while they travel up the call stack.
+type ParseResult<T> = Result<T, Box<ParseError>>;
 
 #[derive(Debug)]
 pub struct Parser {
     pub current: usize,
-    pub tokens: Vec<Token>
+    pub tokens: Vec<Token>,
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
         let current = 0;
-        Parser { current, tokens }
+        let errors = Vec::new();
+        Parser { current, tokens, errors }
     }
 
-    pub fn parse(&mut self) -> Result<Block, &'static str> {
-        self.program()
+    /*
+     * Parse a whole program. Syntax errors are collected rather than
+     * returned on the first failure, so that every error in the file can
+     * be reported at once.
+     */
+    pub fn parse(&mut self) -> Result<Spanned<Block>, Vec<ParseError>> {
+        let program = self.program();
+        if self.errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
     }
 
-    fn program(&mut self) -> Result<Block, &'static str> {
+    /*
+     * Parse a single declaration, statement or expression, optionally
+     * followed by ';' or '.', as typed into the REPL.
+     */
+    pub fn parse_input(&mut self) -> Result<Input, Vec<ParseError>> {
+        let input = match self.input() {
+            Ok(input) => input,
+            Err(err) => {
+                self.errors.push(*err);
+                return Err(std::mem::take(&mut self.errors));
+            }
+        };
+
+        self.match_token(vec![Type::Semicolon, Type::Dot]);
+        if self.errors.is_empty() && !self.is_at_end() {
+            let err = self.error(vec![Type::Semicolon], "unexpected input after statement");
+            self.errors.push(*err);
+        }
+
+        if self.errors.is_empty() {
+            Ok(input)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn input(&mut self) -> ParseResult<Input> {
+        if self.check(Type::Const) {
+            return Ok(Input::Declaration(self.const_decs()?));
+        }
+        if self.check(Type::Var) {
+            return Ok(Input::Declaration(self.var_decs()?));
+        }
+        // The ';' after a routine is optional here like after any other
+        // input, so a declaration is complete as soon as its block ends.
+        if self.check(Type::Procedure) || self.check(Type::Function) {
+            return Ok(Input::Declaration(self.routine()?));
+        }
+
+        let statement = match self.peek().r#type {
+            Type::Identifier => self.tokens.get(self.current + 1)
+                .is_some_and(|t| t.r#type == Type::ColonEqual),
+            Type::Call | Type::Begin | Type::If | Type::While |
+            Type::Bang | Type::Question | Type::Return => true,
+            _ => false,
+        };
+        if statement {
+            Ok(Input::Statement(self.statement()?))
+        } else {
+            Ok(Input::Expression(self.expression()?))
+        }
+    }
+
+    fn program(&mut self) -> Spanned<Block> {
+        let start = self.peek().span;
         let block = self.block();
-        if block.is_err() {
-            return block;
+
+        if !self.match_token(vec![Type::Dot]) {
+            if self.errors.is_empty() {
+                let err = self.error(vec![Type::Dot],
+                                     "expected dot to end program");
+                self.errors.push(*err);
+            } else {
+                // Skip whatever is left of a statement we failed to
+                // recover from instead of reporting the dot as well.
+                while !self.check(Type::Dot) && !self.is_at_end() {
+                    self.advance();
+                }
+                self.match_token(vec![Type::Dot]);
+            }
         }
 
-        let dot = self.expect(Type::Dot, "expected dot to end program");
-        if dot.is_err() {
-            return Err(dot.unwrap_err());
+        self.spanned(Block::Program(Box::new(block)), start)
+    }
+
+    fn block(&mut self) -> Spanned<Block> {
+        let start = self.peek().span;
+
+        let const_decs = self.const_decs().unwrap_or_else(|err| {
+            self.recover(*err);
+            self.match_token(vec![Type::Semicolon]);
+            self.spanned(Block::ConstDecs(Vec::new()), start)
+        });
+
+        let var_decs = self.var_decs().unwrap_or_else(|err| {
+            self.recover(*err);
+            self.match_token(vec![Type::Semicolon]);
+            self.spanned(Block::VarDecs(Vec::new()), start)
+        });
+
+        let mut procedures = Vec::new();
+        while self.check(Type::Procedure) || self.check(Type::Function) {
+            match self.procedure() {
+                Ok(procedure) => procedures.push(procedure),
+                Err(err) => {
+                    self.recover(*err);
+                    self.match_token(vec![Type::Semicolon]);
+                }
+            }
         }
 
-        Ok(Block::Program(Box::new(block.unwrap())))
+        let statement = self.statement().unwrap_or_else(|err| {
+            self.recover(*err);
+            self.spanned(Block::Begin(Vec::new()), start)
+        });
+
+        self.spanned(Block::Block(Box::new(const_decs),
+                                  Box::new(var_decs),
+                                  procedures,
+                                  Box::new(statement)),
+                     start)
     }
 
-    fn block(&mut self) -> Result<Block, &'static str> {
+    fn const_decs(&mut self) -> ParseResult<Spanned<Block>> {
+        let start = self.peek().span;
         let mut const_decs = Vec::new();
         if self.match_token(vec![Type::Const]) {
             loop {
-                let ident = self.expect(Type::Identifier, "identifier");
-                if ident.is_err() {
-                    return Err(ident.unwrap_err());
-                }
-                let ident = Expr::Var(ident.unwrap().lexeme);
+                let dec_start = self.peek().span;
+                let ident = self.expect(Type::Identifier, "expected constant name")?;
+                let ident = Spanned::new(Expr::Var(ident.lexeme), ident.span);
 
-                if !self.match_token(vec![Type::Equal]) {
-                    return Err("expected '=' in const expression");
-                }
+                self.expect(Type::Equal, "expected '=' in const expression")?;
 
-                let number = self.expect(Type::Number, "number");
-                if number.is_err() {
-                    return Err(number.unwrap_err());
-                }
-                let number = Expr::Literal(number.unwrap().literal.unwrap());
+                let number = self.expect(Type::Number, "expected constant value")?;
+                let number = Spanned::new(Expr::Literal(number.literal.unwrap()),
+                                          number.span);
 
-                let const_dec = Block::Const(ident, number);
+                let const_dec = self.spanned(Block::Const(ident, number), dec_start);
                 const_decs.push(const_dec);
 
                 if !self.match_token(vec![Type::Comma]) {
                     break;
                 }
             }
-            let semi = self.expect(Type::Semicolon,
-                                   "missing semicolon after const decs");
-            if semi.is_err() {
-                return Err(semi.unwrap_err());
-            }
+            self.expect(Type::Semicolon, "missing semicolon after const decs")?;
         }
-        let const_decs = Block::ConstDecs(const_decs);
 
+        Ok(self.spanned(Block::ConstDecs(const_decs), start))
+    }
+
+    fn var_decs(&mut self) -> ParseResult<Spanned<Block>> {
+        let start = self.peek().span;
         let mut var_decs = Vec::new();
         if self.match_token(vec![Type::Var]) {
             loop {
-                let ident = self.expect(Type::Identifier, "identifier");
-                if ident.is_err() {
-                    return Err(ident.unwrap_err());
-                }
-                let ident = Expr::Var(ident.unwrap().lexeme);
+                let ident = self.expect(Type::Identifier, "expected variable name")?;
+                let ident = Spanned::new(Expr::Var(ident.lexeme), ident.span);
 
                 var_decs.push(ident);
 
@@ -80,246 +236,289 @@ impl Parser {
                     break;
                 }
             }
-            let semi = self.expect(Type::Semicolon,
-                                   "missing semicolon after var decs");
-            if semi.is_err() {
-                return Err(semi.unwrap_err());
-            }
+            self.expect(Type::Semicolon, "missing semicolon after var decs")?;
         }
-        let var_decs = Block::VarDecs(var_decs);
 
-        let mut procedures = Vec::new();
-        while self.match_token(vec![Type::Procedure]) {
-            let ident = self.expect(Type::Identifier, "missing procedure identifier");
-            if ident.is_err() {
-                return Err(ident.unwrap_err());
-            }
-            let ident = Expr::Var(ident.unwrap().lexeme);
+        Ok(self.spanned(Block::VarDecs(var_decs), start))
+    }
 
-            let semi = self.expect(Type::Semicolon,
-                                   "missing semicolon after procedure identifier");
-            if semi.is_err() {
-                return Err(semi.unwrap_err());
-            }
+    /*
+     * Parse a procedure or function declaration. The two only differ in
+     * the keyword and in how they may be called.
+     */
+    fn procedure(&mut self) -> ParseResult<Spanned<Block>> {
+        let procedure = self.routine()?;
+        self.expect(Type::Semicolon,
+                    "missing semicolon after procedure block")?;
+        Ok(procedure)
+    }
 
-            let block = self.block();
-            if block.is_err() {
-                return Err(block.unwrap_err());
-            }
+    /*
+     * A procedure or function declaration without the ';' that ends it.
+     */
+    fn routine(&mut self) -> ParseResult<Spanned<Block>> {
+        let start = self.peek().span;
+        let is_function = self.match_token(vec![Type::Function]);
+        if !is_function {
+            self.expect(Type::Procedure, "expected procedure")?;
+        }
+
+        let ident = self.expect(Type::Identifier,
+                                "missing procedure identifier")?;
+        let ident = Spanned::new(Expr::Var(ident.lexeme), ident.span);
+
+        let mut params = Vec::new();
+        if self.match_token(vec![Type::LeftParen]) && !self.match_token(vec![Type::RightParen]) {
+            loop {
+                let param = self.expect(Type::Identifier, "expected parameter name")?;
+                params.push(Spanned::new(Expr::Var(param.lexeme), param.span));
 
-            let semi = self.expect(Type::Semicolon,
-                                   "missing semicolon after procedure block");
-            if semi.is_err() {
-                return Err(semi.unwrap_err());
+                if !self.match_token(vec![Type::Comma]) {
+                    break;
+                }
             }
+            self.expect(Type::RightParen, "expected ')' after parameters")?;
+        }
 
-            let procedure = Block::Procedure(ident, Box::new(block.unwrap()));
-            procedures.push(procedure);
+        self.expect(Type::Semicolon,
+                    "missing semicolon after procedure identifier")?;
+
+        let block = self.block();
+
+        if is_function {
+            Ok(self.spanned(Block::Function(ident, params, Box::new(block)), start))
+        } else {
+            Ok(self.spanned(Block::Procedure(ident, params, Box::new(block)), start))
         }
+    }
+
+    /*
+     * Parse an optional parenthesised argument list following a call.
+     */
+    fn arguments(&mut self) -> ParseResult<Vec<Spanned<Expr>>> {
+        let mut args = Vec::new();
+        if self.match_token(vec![Type::LeftParen]) && !self.match_token(vec![Type::RightParen]) {
+            loop {
+                args.push(self.expression()?);
 
-        let statement = self.statement();
-        if statement.is_err() {
-            return Err(statement.unwrap_err());
+                if !self.match_token(vec![Type::Comma]) {
+                    break;
+                }
+            }
+            self.expect(Type::RightParen, "expected ')' after arguments")?;
         }
 
-        Ok(Block::Block(Box::new(const_decs),
-                        Box::new(var_decs),
-                        procedures,
-                        Box::new(statement.unwrap())))
+        Ok(args)
     }
 
-    fn statement(&mut self) -> Result<Block, &'static str> {
+    fn statement(&mut self) -> ParseResult<Spanned<Block>> {
+        let start = self.peek().span;
+
         // Assignment
         if self.match_token(vec![Type::Identifier]) {
             let var = self.previous();
+            let var = Spanned::new(Expr::Var(var.lexeme), var.span);
 
-            let coloneq = self.expect(Type::ColonEqual, "missing colon equal");
-            if coloneq.is_err() {
-                return Err(coloneq.unwrap_err())
-            }
+            self.expect(Type::ColonEqual, "missing colon equal")?;
 
-            let right = self.expression();
-            if right.is_ok() {
-                return Ok(Block::Assign(Expr::Var(var.lexeme), right.unwrap()));
-            } else {
-                return Err(right.unwrap_err());
-            }
+            let right = self.expression()?;
+            Ok(self.spanned(Block::Assign(var, right), start))
 
         // Call Statement
         } else if self.match_token(vec![Type::Call]) {
-            let ident = self.expect(Type::Identifier, "call missing identifier");
-            if ident.is_err() {
-                return Err(ident.unwrap_err());
-            }
+            let ident = self.expect(Type::Identifier, "call missing identifier")?;
 
-            let ident = Expr::Var(ident.unwrap().lexeme);
-            return Ok(Block::Call(ident));
+            let ident = Spanned::new(Expr::Var(ident.lexeme), ident.span);
+            let args = self.arguments()?;
+            Ok(self.spanned(Block::Call(ident, args), start))
 
         // Begin block
         } else if self.match_token(vec![Type::Begin]) {
             let mut statements = Vec::new();
             loop {
-                let statement = self.statement();
-                if statement.is_err() {
-                    return statement;
+                match self.statement() {
+                    Ok(statement) => statements.push(statement),
+                    Err(err) => {
+                        self.recover(*err);
+                        // Recovery can stop at the start of the next
+                        // statement, with no ';' in between.
+                        if self.check(Type::Begin) {
+                            continue;
+                        }
+                    },
                 }
 
-                statements.push(statement.unwrap());
                 if !self.match_token(vec![Type::Semicolon]) {
                     break;
                 }
             }
-            let end = self.expect(Type::End, "missing end keyword or semicolon");
-            if end.is_err() {
-                return Err(end.unwrap_err());
-            }
-            return Ok(Block::Begin(statements));
+            self.expect(Type::End, "missing end keyword or semicolon")?;
+            Ok(self.spanned(Block::Begin(statements), start))
 
         // If block
         } else if self.match_token(vec![Type::If]) {
-            let condition = self.condition();
-            if condition.is_err() {
-                return Err(condition.unwrap_err());
-            }
+            let condition = self.condition()?;
 
-            let then = self.expect(Type::Then, "missing then keyword");
-            if then.is_err() {
-                return Err(then.unwrap_err());
-            }
+            self.expect(Type::Then, "missing then keyword")?;
 
-            let body = self.statement();
+            let body = self.statement()?;
 
-            if body.is_ok() {
-                return Ok(Block::If(condition.unwrap(),
-                                    Box::new(body.unwrap())));
+            let alternative = if self.match_token(vec![Type::Else]) {
+                Some(Box::new(self.statement()?))
             } else {
-                return condition.and(body);
-            }
+                None
+            };
+            Ok(self.spanned(Block::If(condition, Box::new(body), alternative), start))
 
         // While block
         } else if self.match_token(vec![Type::While]) {
-            let condition = self.condition();
+            let condition = self.condition()?;
 
-            let do_keyword = self.expect(Type::Do, "missing do keyword");
-            if do_keyword.is_err() {
-                return Err(do_keyword.unwrap_err());
-            }
+            self.expect(Type::Do, "missing do keyword")?;
 
-            let body = self.statement();
-
-            if condition.is_ok() && body.is_ok() {
-                return Ok(Block::While(condition.unwrap(),
-                                       Box::new(body.unwrap())));
-            } else {
-                return condition.and(body);
-            }
+            let body = self.statement()?;
+            Ok(self.spanned(Block::While(condition, Box::new(body)), start))
 
         // WriteLn
         } else if self.match_token(vec![Type::Bang]) {
-            let expression = self.expression();
+            let expression = self.expression()?;
 
-            if expression.is_err() {
-                return Err(expression.unwrap_err());
-            }
+            Ok(self.spanned(Block::WriteLn(expression), start))
 
-            return Ok(Block::WriteLn(expression.unwrap()));
-        }
+        // Read
+        } else if self.match_token(vec![Type::Question]) {
+            let ident = self.expect(Type::Identifier, "read missing identifier")?;
+
+            let ident = Spanned::new(Expr::Var(ident.lexeme), ident.span);
+            Ok(self.spanned(Block::Read(ident), start))
+
+        // Return
+        } else if self.match_token(vec![Type::Return]) {
+            let expression = self.expression()?;
 
-        Err("statement error")
+            Ok(self.spanned(Block::Return(expression), start))
+        } else {
+            Err(self.error(vec![Type::Identifier, Type::Call, Type::Begin,
+                                Type::If, Type::While, Type::Bang,
+                                Type::Question, Type::Return],
+                           "expected statement"))
+        }
     }
 
-    fn condition(&mut self) -> Result<Expr, &'static str> {
+    fn condition(&mut self) -> ParseResult<Spanned<Expr>> {
+        let start = self.peek().span;
         if self.match_token(vec![Type::Odd]) {
-            let expr = self.expression();
-            if expr.is_ok() {
-                Ok(Expr::OddExpr(Box::new(expr.unwrap())))
-            } else {
-                expr
-            }
+            let expr = self.expression()?;
+            Ok(self.spanned(Expr::OddExpr(Box::new(expr)), start))
         } else {
-            let expr = self.expression();
-            if self.match_token(vec![Type::Less, Type::LessEqual,
-                                     Type::Greater, Type::GreaterEqual,
-                                     Type::Hash, Type::Equal]) {
+            let expr = self.expression()?;
+            let operators = vec![Type::Less, Type::LessEqual,
+                                 Type::Greater, Type::GreaterEqual,
+                                 Type::Hash, Type::Equal];
+            if self.match_token(operators.clone()) {
                 let operator = self.previous();
-                let right = self.expression();
-                if expr.is_ok() && right.is_ok() {
-                    Ok(Expr::Expr(Box::new(expr.unwrap()), operator.r#type, Box::new(right.unwrap())))
-                } else {
-                    expr.and(right)
-                }
+                let right = self.expression()?;
+                let expr = Expr::Expr(Box::new(expr), operator.r#type, Box::new(right));
+                Ok(self.spanned(expr, start))
             } else {
-                Err("invalid condition")
+                Err(self.error(operators, "invalid condition"))
             }
         }
     }
 
-    fn expression(&mut self) -> Result<Expr, &'static str> {
-        let prefix;
-        if self.match_token(vec![Type::Plus, Type::Minus]) {
-            prefix = Some(self.previous().r#type);
+    fn expression(&mut self) -> ParseResult<Spanned<Expr>> {
+        let start = self.peek().span;
+        let prefix = if self.match_token(vec![Type::Plus, Type::Minus]) {
+            Some(self.previous().r#type)
         } else {
-            prefix = None
-        }
+            None
+        };
 
-        let mut term = self.term();
+        let term_start = self.peek().span;
+        let mut term = self.term()?;
         while self.match_token(vec![Type::Plus, Type::Minus]) {
             let operator = self.previous();
-            let right = self.term();
-            if term.is_ok() && right.is_ok() {
-                term = Ok(Expr::Expr(Box::new(term.unwrap()), operator.r#type, Box::new(right.unwrap())));
-            } else {
-                return term.and(right);
-            }
+            let right = self.term()?;
+            let expr = Expr::Expr(Box::new(term), operator.r#type, Box::new(right));
+            term = self.spanned(expr, term_start);
         }
 
-        if term.is_ok() {
-            Ok(Expr::PrefixExpr(prefix, Box::new(term.unwrap())))
-        } else {
-            term
-        }
+        Ok(self.spanned(Expr::PrefixExpr(prefix, Box::new(term)), start))
     }
 
-    fn term(&mut self) -> Result<Expr, &'static str> {
-        let mut factor = self.factor();
+    fn term(&mut self) -> ParseResult<Spanned<Expr>> {
+        let start = self.peek().span;
+        let mut factor = self.factor()?;
         while self.match_token(vec![Type::Star, Type::Slash]) {
             let operator = self.previous();
-            let right = self.factor();
-
-            if factor.is_ok() && right.is_ok() {
-                factor = Ok(Expr::Expr(Box::new(factor.unwrap()), operator.r#type, Box::new(right.unwrap())))
-            } else {
-                return factor.and(right);
-            }
+            let right = self.factor()?;
+            let expr = Expr::Expr(Box::new(factor), operator.r#type, Box::new(right));
+            factor = self.spanned(expr, start);
         }
 
-        factor
+        Ok(factor)
     }
 
-    fn factor(&mut self) -> Result<Expr, &'static str> {
+    fn factor(&mut self) -> ParseResult<Spanned<Expr>> {
+        let start = self.peek().span;
         if self.match_token(vec![Type::Identifier]) {
-            return Ok(Expr::Var(self.previous().lexeme));
+            let ident = self.previous();
+            if self.check(Type::LeftParen) {
+                let args = self.arguments()?;
+                return Ok(self.spanned(Expr::Call(ident.lexeme, args), start));
+            }
+            return Ok(Spanned::new(Expr::Var(ident.lexeme), ident.span));
         }
 
         if self.match_token(vec![Type::Number]) {
-            return Ok(Expr::Literal(self.previous().literal.unwrap()));
+            let number = self.previous();
+            return Ok(Spanned::new(Expr::Literal(number.literal.unwrap()),
+                                   number.span));
         }
 
         if self.match_token(vec![Type::LeftParen]) {
-            let expr = self.expression();
-            let paren = self.expect(Type::RightParen, "expected matching ')'");
-            if paren.is_err() {
-                return Err(paren.unwrap_err());
-            }
+            let expr = self.expression()?;
+            self.expect(Type::RightParen, "expected matching ')'")?;
 
-            if expr.is_err() {
-                return Err(expr.unwrap_err());
-            }
+            return Ok(self.spanned(Expr::Group(Box::new(expr)), start));
+        }
+
+        Err(self.error(vec![Type::Identifier, Type::Number, Type::LeftParen],
+                       "expected expression"))
+    }
+
+    /*
+     * Wrap a node with the span running from `start` to the end of the
+     * last consumed token.
+     */
+    fn spanned<T>(&self, node: T, start: Span) -> Spanned<T> {
+        let end = self.tokens[self.current.saturating_sub(1)].span;
+        Spanned::new(node, start.to(end))
+    }
+
+    /*
+     * Record an error and skip ahead to the next token a statement or
+     * declaration can be resumed from.
+     */
+    fn recover(&mut self, err: ParseError) {
+        self.errors.push(err);
+        self.synchronize();
+    }
 
-            return Ok(Expr::Group(Box::new(expr.unwrap())));
+    fn synchronize(&mut self) {
+        while !self.is_at_end() {
+            match self.peek().r#type {
+                Type::Semicolon | Type::End | Type::Dot => return,
+                Type::Begin | Type::Procedure | Type::Function |
+                Type::Const | Type::Var => return,
+                _ => { self.advance(); },
+            }
         }
+    }
 
-        Err("expected expression")
+    fn error(&self, expected: Vec<Type>, message: &'static str) -> Box<ParseError> {
+        let found = self.peek();
+        Box::new(ParseError { span: found.span, expected, found, message })
     }
 
     fn match_token(&mut self, tokens: Vec<Type>) -> bool {
@@ -335,13 +534,13 @@ impl Parser {
 
     fn expect(&mut self,
               token: Type,
-              err: &'static str) -> Result<Token, &'static str> {
+              message: &'static str) -> ParseResult<Token> {
         if self.check(token) {
             let token: Token = self.tokens[self.current].clone();
             self.advance();
             return Ok(token);
         }
-        Err(err)
+        Err(self.error(vec![token], message))
     }
 
     fn check(&mut self, token_type: Type) -> bool {
@@ -372,3 +571,118 @@ impl Parser {
         self.tokens[self.current - 1].clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Result<Spanned<Block>, Vec<ParseError>> {
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        Parser::new(tokens).parse()
+    }
+
+    /*
+     * The line, column and message of every error in `source`.
+     */
+    fn errors(source: &str) -> Vec<(u32, u32, &'static str)> {
+        parse(source)
+            .expect_err("expected syntax errors")
+            .iter()
+            .map(|err| (err.span.line, err.span.column, err.message))
+            .collect()
+    }
+
+    #[test]
+    fn valid_program() {
+        assert!(parse("const k = 1; var x;\n\
+                       procedure p(a); begin x := a end;\n\
+                       function f; begin f := k end;\n\
+                       begin call p(f()); if odd x then ! x else ? x end.").is_ok());
+    }
+
+    #[test]
+    fn every_error_in_a_file_is_reported() {
+        let source = "var x\n\
+                      begin\n  \
+                        x := ;\n  \
+                        if x then ! 1;\n  \
+                        ! (x\n\
+                      end.";
+        assert_eq!(errors(source), vec![
+            (2, 1, "missing semicolon after var decs"),
+            (3, 8, "expected expression"),
+            (4, 8, "invalid condition"),
+            (6, 1, "expected matching ')'"),
+        ]);
+    }
+
+    #[test]
+    fn recovery_stops_at_declarations() {
+        let source = "const k = ;\n\
+                      var x;\n\
+                      procedure p;\n\
+                      begin\n  \
+                        x := 1 +\n\
+                      end\n\
+                      procedure q;\n\
+                      begin\n  \
+                        call\n\
+                      end;\n\
+                      begin\n  \
+                        ! k\n\
+                      end.";
+        assert_eq!(errors(source), vec![
+            (1, 11, "expected constant value"),
+            (6, 1, "expected expression"),
+            (7, 1, "missing semicolon after procedure block"),
+            (10, 1, "call missing identifier"),
+        ]);
+    }
+
+    #[test]
+    fn recovery_resumes_at_a_begin_statement() {
+        let source = "var x;\n\
+                      begin\n  \
+                        if x then begin ! 1 end;\n  \
+                        x := 1 2;\n  \
+                        while x do ! x\n\
+                      end.";
+        assert_eq!(errors(source), vec![
+            (3, 8, "invalid condition"),
+            (4, 10, "missing end keyword or semicolon"),
+        ]);
+    }
+
+    #[test]
+    fn missing_dot() {
+        assert_eq!(errors("begin ! 1 end"), vec![(1, 14, "expected dot to end program")]);
+    }
+
+    fn input(source: &str) -> Result<Input, Vec<ParseError>> {
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        Parser::new(tokens).parse_input()
+    }
+
+    #[test]
+    fn input_routine_needs_no_semicolon() {
+        for source in ["procedure p;\nbegin ! 1 end\n",
+                       "procedure p;\nbegin ! 1 end;\n",
+                       "function f(n); begin f := n end.",
+                       "function f(n); var x; procedure q; x := n; begin call q; f := x end"] {
+            assert!(matches!(input(source), Ok(Input::Declaration(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn input_nested_routine_needs_a_semicolon() {
+        let errs = input("procedure p; procedure q; ! 1 begin call q end").unwrap_err();
+        assert_eq!(errs[0].message, "missing semicolon after procedure block");
+    }
+
+    #[test]
+    fn input_after_a_routine() {
+        let errs = input("procedure p; ! 1; ! 2").unwrap_err();
+        assert_eq!(errs[0].message, "unexpected input after statement");
+    }
+}
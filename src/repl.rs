@@ -0,0 +1,359 @@
+use std::io::{self, BufRead, Write};
+
+use crate::ast::{Block, Expr, Span, Spanned};
+use crate::interp::{self, EnvVal, Interp};
+use crate::io::{Io, StdIo};
+use crate::irgen::IRGen;
+use crate::parser::{Input, Parser};
+use crate::resolver::Resolver;
+use crate::scanner::Scanner;
+
+/*
+ * The outcome of parsing the text typed so far.
+ */
+#[derive(Debug)]
+enum Parsed {
+    Complete(Input),
+    // More lines could still make it valid.
+    Incomplete(Vec<String>),
+    Invalid(Vec<String>),
+}
+
+fn parse(source: &str) -> Parsed {
+    let mut scanner = Scanner::new(source.chars().collect());
+    let tokens = match scanner.scan_tokens() {
+        Ok(tokens) => tokens,
+        Err(errs) => return Parsed::Invalid(errs.iter().map(|e| e.to_string()).collect()),
+    };
+
+    match Parser::new(tokens).parse_input() {
+        Ok(input) => Parsed::Complete(input),
+        Err(errs) => {
+            let messages = errs.iter().map(|e| e.to_string()).collect();
+            if errs.iter().any(|e| e.is_incomplete()) {
+                Parsed::Incomplete(messages)
+            } else {
+                Parsed::Invalid(messages)
+            }
+        }
+    }
+}
+
+/*
+ * An interactive session. Every declaration lands in the interpreter's
+ * global frame and stays visible to later input. The declarations are
+ * also kept as syntax so the IR of the last input can be generated in
+ * the context of everything declared before it. Results and command
+ * output go through the interpreter's `Io`, errors to stderr.
+ */
+pub struct Repl<I: Io = StdIo> {
+    interp: Interp<I>,
+    resolver: Resolver,
+    consts: Vec<Spanned<Block>>,
+    vars: Vec<Spanned<Expr>>,
+    procs: Vec<Spanned<Block>>,
+    last: Option<Input>,
+    // Lines that do not make a complete input yet.
+    buffer: String,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl::with_io(StdIo::new())
+    }
+
+    /*
+     * Read input from stdin until it is closed or the user quits.
+     */
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        loop {
+            print!("{}", if self.buffer.is_empty() { "> " } else { "... " });
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(n) if n > 0 => (),
+                _ => {
+                    println!();
+                    break;
+                }
+            }
+
+            if !self.line(&line) {
+                break;
+            }
+        }
+    }
+}
+
+impl<I: Io> Repl<I> {
+    pub fn with_io(io: I) -> Repl<I> {
+        Repl {
+            interp: Interp::with_io(io),
+            resolver: Resolver::new(),
+            consts: Vec::new(),
+            vars: Vec::new(),
+            procs: Vec::new(),
+            last: None,
+            buffer: String::new(),
+        }
+    }
+
+    pub fn io(&self) -> &I {
+        self.interp.io()
+    }
+
+    /*
+     * Handle one line of input, returning false when the session should
+     * end. Input that ends part way through, such as an open `begin`,
+     * continues on the next line; an empty line gives up on it.
+     */
+    pub fn line(&mut self, line: &str) -> bool {
+        let text = line.trim();
+        if self.buffer.is_empty() {
+            if text.is_empty() {
+                return true;
+            }
+            if text.starts_with(':') {
+                return self.command(text);
+            }
+        }
+
+        self.buffer.push_str(line);
+        if !line.ends_with('\n') {
+            self.buffer.push('\n');
+        }
+        match parse(&self.buffer) {
+            Parsed::Incomplete(_) if !text.is_empty() => return true,
+            Parsed::Incomplete(errors) | Parsed::Invalid(errors) => {
+                for err in errors.iter() {
+                    eprintln!("{}", err);
+                }
+            },
+            Parsed::Complete(input) => self.eval(input),
+        }
+        self.buffer.clear();
+        true
+    }
+
+    fn eval(&mut self, input: Input) {
+        let resolved = match &input {
+            Input::Declaration(block) | Input::Statement(block) =>
+                self.resolver.resolve_global(block),
+            Input::Expression(expr) => self.resolver.resolve_global_expr(expr),
+        };
+        if let Err(errs) = resolved {
+            for err in errs.iter() {
+                eprintln!("{}", err);
+            }
+            return;
+        }
+        self.last = Some(input.clone());
+
+        let result = match input {
+            Input::Declaration(decl) => {
+                self.remember(&decl);
+                self.interp.declare(decl);
+                Ok(())
+            },
+            Input::Statement(stmt) => self.interp.eval(stmt),
+            Input::Expression(expr) => {
+                self.interp.eval_expr(expr).map(|val| self.interp.io_mut().write_int(val))
+            },
+        };
+
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            let calls = err.trace.iter()
+                .map(|(procedure, span)| format!("in '{}' called at {}", procedure, span));
+            interp::print_calls(calls);
+        }
+    }
+
+    fn remember(&mut self, decl: &Spanned<Block>) {
+        match &decl.node {
+            Block::ConstDecs(consts) => self.consts.extend(consts.iter().cloned()),
+            Block::VarDecs(vars) => self.vars.extend(vars.iter().cloned()),
+            Block::Procedure(..) | Block::Function(..) => self.procs.push(decl.clone()),
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, text: &str) {
+        let io = self.interp.io_mut();
+        io.write_str(text);
+        io.write_str("\n");
+    }
+
+    /*
+     * Run a `:` command, returning false when the session should end.
+     */
+    fn command(&mut self, command: &str) -> bool {
+        match command {
+            ":env" => self.print_env(),
+            ":ast" => {
+                let text = match &self.last {
+                    Some(input) => format!("{:#?}", input),
+                    None => "no input yet".to_string(),
+                };
+                self.print(&text);
+            },
+            ":ir" => self.print_ir(),
+            ":quit" | ":q" => return false,
+            ":help" => {
+                self.print(":env    show the global environment");
+                self.print(":ast    show the syntax tree of the last input");
+                self.print(":ir     show the IR of the last input");
+                self.print(":quit   leave the REPL");
+            },
+            _ => eprintln!("unknown command '{}', try :help", command),
+        }
+        true
+    }
+
+    fn print_env(&mut self) {
+        let globals = &self.interp.env.frames()[0].vars;
+        let mut names: Vec<&String> = globals.keys().collect();
+        names.sort();
+        let lines: Vec<String> = names.into_iter()
+            .map(|name| match &globals[name] {
+                EnvVal::Number(n) => format!("{} = {}", name, n),
+                EnvVal::ProcVal(params, _) => format!("procedure {}({})", name, params.join(", ")),
+                EnvVal::FuncVal(params, _) => format!("function {}({})", name, params.join(", ")),
+            })
+            .collect();
+        for line in lines.iter() {
+            self.print(line);
+        }
+    }
+
+    /*
+     * Generate the IR of a program made of every declaration so far with
+     * the last input as its statement.
+     */
+    fn print_ir(&mut self) {
+        let span = Span::default();
+        let statement = match &self.last {
+            Some(Input::Statement(stmt)) => stmt.clone(),
+            Some(Input::Expression(expr)) =>
+                Spanned::new(Block::WriteLn(expr.clone()), expr.span),
+            Some(Input::Declaration(_)) => Spanned::new(Block::Begin(Vec::new()), span),
+            None => {
+                self.print("no input yet");
+                return;
+            }
+        };
+
+        let block = Block::Block(
+            Box::new(Spanned::new(Block::ConstDecs(self.consts.clone()), span)),
+            Box::new(Spanned::new(Block::VarDecs(self.vars.clone()), span)),
+            self.procs.clone(),
+            Box::new(statement));
+        let program = Spanned::new(Block::Program(Box::new(Spanned::new(block, span))), span);
+
+        let mut irgen = IRGen::new();
+        irgen.gen(program);
+        for line in irgen.code.iter() {
+            self.print(&line.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::BufferIo;
+
+    fn session(lines: &[&str]) -> Repl<BufferIo> {
+        let mut repl = Repl::with_io(BufferIo::new(""));
+        for line in lines {
+            assert!(repl.line(line), "{}", line);
+        }
+        repl
+    }
+
+    #[test]
+    fn open_input_is_incomplete() {
+        for source in ["begin ! 1", "procedure p;", "var x", "(1 + 2"] {
+            assert!(matches!(parse(source), Parsed::Incomplete(_)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn broken_input_is_invalid() {
+        for source in ["begin ! 1 ) end", "1 +* 2", "x := := 1", "$"] {
+            assert!(matches!(parse(source), Parsed::Invalid(_)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn begin_end_over_several_lines() {
+        let repl = session(&["var x;", "begin", "x := 2;", "! x * 3", "end", "x + 1"]);
+        assert_eq!(repl.io().output(), "6\n3\n");
+    }
+
+    #[test]
+    fn empty_line_gives_up_on_incomplete_input() {
+        let mut repl = session(&["begin", "! 1", ""]);
+        assert!(repl.buffer.is_empty());
+        assert!(repl.last.is_none());
+        assert!(repl.line("! 2"));
+        assert_eq!(repl.io().output(), "2\n");
+    }
+
+    #[test]
+    fn rejected_redeclaration_is_not_kept() {
+        let mut repl = session(&["procedure p; begin ! 1 end", "1"]);
+        assert!(repl.line("procedure p; begin ! 2 end"));
+        assert_eq!(repl.procs.len(), 1);
+        assert!(matches!(repl.last, Some(Input::Expression(_))));
+
+        assert!(repl.line("call p"));
+        assert_eq!(repl.io().output(), "1\n1\n");
+    }
+
+    #[test]
+    fn env_lists_globals_by_name() {
+        let repl = session(&["const c = 7;", "var x;", "function f(a, b); f := a", "x := 3", ":env"]);
+        assert_eq!(repl.io().output(), "c = 7\nfunction f(a, b)\nx = 3\n");
+    }
+
+    #[test]
+    fn ast_shows_the_last_input() {
+        let repl = session(&[":ast", "1 + 2", ":ast"]);
+        let output = repl.io().output();
+        assert!(output.starts_with("no input yet\n3\nExpression("), "{}", output);
+    }
+
+    #[test]
+    fn ir_after_a_procedure_declaration() {
+        let repl = session(&[":ir", "procedure p;", "begin ! 1 end", ":ir"]);
+        let lines: Vec<&str> = repl.io().output().lines().map(|line| line.trim()).collect();
+        assert_eq!(lines[0], "no input yet");
+        assert!(lines[1].ends_with("FUNC"), "{:?}", lines);
+        assert_eq!(&lines[2..], ["ENTER 1,0", "LOADC 1", "WRITE", "RET", "HALT"]);
+    }
+
+    #[test]
+    fn ir_of_an_expression_writes_it() {
+        let repl = session(&["const c = 4;", "c * 2", ":ir"]);
+        let output = repl.io().output();
+        assert!(output.starts_with("8\n"), "{}", output);
+        assert!(output.contains("WRITE"), "{}", output);
+    }
+
+    #[test]
+    fn quit_ends_the_session() {
+        let mut repl = session(&[]);
+        assert!(repl.line(":help"));
+        assert!(!repl.line(":q"));
+        assert!(!repl.line(":quit"));
+    }
+}
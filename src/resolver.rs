@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{Block, Expr, Span, Spanned};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Symbol {
+    Const,
+    Var,
+    // Procedures and functions carry their number of parameters.
+    Procedure(usize),
+    Function(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveErrorKind {
+    Undeclared(String),
+    AssignToConst(String),
+    CallOfNonProcedure(String),
+    ProcedureAsValue(String),
+    DuplicateDeclaration(String),
+    ArityMismatch { name: String, expected: usize, found: usize },
+    CallOfFunction(String),
+    CallOfNonFunction(String),
+    FunctionAsValue(String),
+    AssignToFunction(String),
+    ReturnOutsideFunction,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolveError {
+    pub span: Span,
+    pub kind: ResolveErrorKind,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ResolveErrorKind::Undeclared(name) =>
+                write!(f, "{}: '{}' is not declared", self.span, name),
+            ResolveErrorKind::AssignToConst(name) =>
+                write!(f, "{}: cannot assign to constant '{}'", self.span, name),
+            ResolveErrorKind::CallOfNonProcedure(name) =>
+                write!(f, "{}: '{}' is not a procedure", self.span, name),
+            ResolveErrorKind::ProcedureAsValue(name) =>
+                write!(f, "{}: procedure '{}' used as a value", self.span, name),
+            ResolveErrorKind::DuplicateDeclaration(name) =>
+                write!(f, "{}: '{}' is already declared in this block",
+                       self.span, name),
+            ResolveErrorKind::ArityMismatch { name, expected, found } =>
+                write!(f, "{}: '{}' takes {} argument(s) but {} were given",
+                       self.span, name, expected, found),
+            ResolveErrorKind::CallOfFunction(name) =>
+                write!(f, "{}: function '{}' must be called from an expression",
+                       self.span, name),
+            ResolveErrorKind::CallOfNonFunction(name) =>
+                write!(f, "{}: '{}' is not a function", self.span, name),
+            ResolveErrorKind::FunctionAsValue(name) =>
+                write!(f, "{}: function '{}' used without an argument list",
+                       self.span, name),
+            ResolveErrorKind::AssignToFunction(name) =>
+                write!(f, "{}: the result of '{}' can only be set inside its body",
+                       self.span, name),
+            ResolveErrorKind::ReturnOutsideFunction =>
+                write!(f, "{}: return outside of a function", self.span),
+        }
+    }
+}
+
+/*
+ * Checks that every name in a program refers to a declaration of the
+ * right kind before any backend sees it. Scoping follows the backends:
+ * a block sees its own declarations and those of every enclosing block,
+ * and a procedure is visible inside its own body.
+ */
+#[derive(Default)]
+pub struct Resolver {
+    scopes: Vec<HashMap<String, Symbol>>,
+    // The function whose result the current body may assign, or None
+    // inside a procedure or the main program.
+    functions: Vec<Option<String>>,
+    errors: Vec<ResolveError>,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver {
+            scopes: Vec::new(),
+            functions: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn resolve(&mut self, program: &Spanned<Block>) -> Result<(), Vec<ResolveError>> {
+        self.resolve_block(program);
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /*
+     * Resolve a declaration or statement at the top level of a program
+     * that is built up one piece at a time, as in the REPL. Declarations
+     * are kept for later calls, unless they fail to resolve.
+     */
+    pub fn resolve_global(&mut self, block: &Spanned<Block>) -> Result<(), Vec<ResolveError>> {
+        if self.scopes.is_empty() {
+            self.scopes.push(HashMap::new());
+        }
+        let saved = self.scopes.clone();
+        self.resolve_block(block);
+        self.finish_global(saved)
+    }
+
+    pub fn resolve_global_expr(&mut self, expr: &Spanned<Expr>) -> Result<(), Vec<ResolveError>> {
+        if self.scopes.is_empty() {
+            self.scopes.push(HashMap::new());
+        }
+        let saved = self.scopes.clone();
+        self.resolve_expr(expr);
+        self.finish_global(saved)
+    }
+
+    fn finish_global(&mut self, saved: Vec<HashMap<String, Symbol>>) -> Result<(), Vec<ResolveError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            self.scopes = saved;
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn resolve_block(&mut self, block: &Spanned<Block>) {
+        match &block.node {
+            Block::Program(p) => self.resolve_block(p),
+            Block::Block(..) => self.resolve_scope(block, &[]),
+            Block::ConstDecs(cds) => {
+                for cd in cds {
+                    if let Block::Const(name, _) = &cd.node {
+                        self.declare(name, Symbol::Const);
+                    }
+                }
+            },
+            Block::VarDecs(vds) => {
+                for v in vds {
+                    self.declare(v, Symbol::Var);
+                }
+            },
+            Block::Procedure(name, params, body) => {
+                self.declare(name, Symbol::Procedure(params.len()));
+                self.functions.push(None);
+                self.resolve_scope(body, params);
+                self.functions.pop();
+            },
+            Block::Function(name, params, body) => {
+                self.declare(name, Symbol::Function(params.len()));
+                if let Expr::Var(v) = &name.node {
+                    self.functions.push(Some(v.clone()));
+                }
+                self.resolve_scope(body, params);
+                self.functions.pop();
+            },
+            Block::Return(expr) => {
+                if self.current_function().is_none() {
+                    self.error(block.span, ResolveErrorKind::ReturnOutsideFunction);
+                }
+                self.resolve_expr(expr);
+            },
+            Block::Begin(stmts) => {
+                for stmt in stmts {
+                    self.resolve_block(stmt);
+                }
+            },
+            Block::Assign(var, expr) => {
+                self.resolve_target(var);
+                self.resolve_expr(expr);
+            },
+            Block::Read(var) => self.resolve_target(var),
+            Block::Call(name, args) => {
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+
+                if let Expr::Var(v) = &name.node {
+                    match self.lookup(v) {
+                        Some(Symbol::Procedure(arity)) if arity != args.len() => {
+                            self.error(name.span, ResolveErrorKind::ArityMismatch {
+                                name: v.clone(),
+                                expected: arity,
+                                found: args.len(),
+                            });
+                        },
+                        Some(Symbol::Procedure(_)) => (),
+                        Some(Symbol::Function(_)) => self.error(name.span,
+                            ResolveErrorKind::CallOfFunction(v.clone())),
+                        Some(_) => self.error(name.span,
+                            ResolveErrorKind::CallOfNonProcedure(v.clone())),
+                        None => self.error(name.span,
+                            ResolveErrorKind::Undeclared(v.clone())),
+                    }
+                }
+            },
+            Block::If(expr, body, alternative) => {
+                self.resolve_expr(expr);
+                self.resolve_block(body);
+                if let Some(alternative) = alternative {
+                    self.resolve_block(alternative);
+                }
+            },
+            Block::While(expr, body) => {
+                self.resolve_expr(expr);
+                self.resolve_block(body);
+            },
+            Block::WriteLn(expr) => self.resolve_expr(expr),
+            Block::Const(..) => (),
+        }
+    }
+
+    /*
+     * Resolve a block in a new scope. Parameters share the scope of the
+     * procedure's own declarations.
+     */
+    fn resolve_scope(&mut self, block: &Spanned<Block>, params: &[Spanned<Expr>]) {
+        self.scopes.push(HashMap::new());
+        for param in params {
+            self.declare(param, Symbol::Var);
+        }
+
+        if let Block::Block(consts, vars, procs, stmts) = &block.node {
+            self.resolve_block(consts);
+            self.resolve_block(vars);
+            for p in procs {
+                self.resolve_block(p);
+            }
+            self.resolve_block(stmts);
+        }
+        self.scopes.pop();
+    }
+
+    fn resolve_expr(&mut self, expr: &Spanned<Expr>) {
+        match &expr.node {
+            Expr::Var(v) => {
+                match self.lookup(v) {
+                    Some(Symbol::Procedure(_)) => self.error(expr.span,
+                        ResolveErrorKind::ProcedureAsValue(v.clone())),
+                    Some(Symbol::Function(_)) => self.error(expr.span,
+                        ResolveErrorKind::FunctionAsValue(v.clone())),
+                    Some(_) => (),
+                    None => self.error(expr.span,
+                        ResolveErrorKind::Undeclared(v.clone())),
+                }
+            },
+            Expr::Call(v, args) => {
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+
+                match self.lookup(v) {
+                    Some(Symbol::Function(arity)) if arity != args.len() => {
+                        self.error(expr.span, ResolveErrorKind::ArityMismatch {
+                            name: v.clone(),
+                            expected: arity,
+                            found: args.len(),
+                        });
+                    },
+                    Some(Symbol::Function(_)) => (),
+                    Some(Symbol::Procedure(_)) => self.error(expr.span,
+                        ResolveErrorKind::ProcedureAsValue(v.clone())),
+                    Some(_) => self.error(expr.span,
+                        ResolveErrorKind::CallOfNonFunction(v.clone())),
+                    None => self.error(expr.span,
+                        ResolveErrorKind::Undeclared(v.clone())),
+                }
+            },
+            Expr::Expr(left, _, right) => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            },
+            Expr::OddExpr(e) | Expr::PrefixExpr(_, e) | Expr::Group(e) => {
+                self.resolve_expr(e);
+            },
+            Expr::Literal(_) => (),
+        }
+    }
+
+    /*
+     * Check the left hand side of an assignment or read statement.
+     */
+    fn resolve_target(&mut self, var: &Spanned<Expr>) {
+        if let Expr::Var(v) = &var.node {
+            match self.lookup(v) {
+                Some(Symbol::Var) => (),
+                Some(Symbol::Const) => self.error(var.span,
+                    ResolveErrorKind::AssignToConst(v.clone())),
+                Some(Symbol::Procedure(_)) => self.error(var.span,
+                    ResolveErrorKind::ProcedureAsValue(v.clone())),
+                // A function's result is set by assigning to its name
+                // from inside its own body.
+                Some(Symbol::Function(_)) => {
+                    if self.current_function() != Some(v.as_str()) {
+                        self.error(var.span,
+                                   ResolveErrorKind::AssignToFunction(v.clone()));
+                    }
+                },
+                None => self.error(var.span,
+                    ResolveErrorKind::Undeclared(v.clone())),
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Spanned<Expr>, symbol: Symbol) {
+        if let Expr::Var(v) = &name.node {
+            let scope = self.scopes.last_mut().unwrap();
+            if scope.contains_key(v) {
+                self.error(name.span,
+                           ResolveErrorKind::DuplicateDeclaration(v.clone()));
+            } else {
+                scope.insert(v.clone(), symbol);
+            }
+        }
+    }
+
+    fn current_function(&self) -> Option<&str> {
+        self.functions.last().and_then(|f| f.as_deref())
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.scopes.iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    fn error(&mut self, span: Span, kind: ResolveErrorKind) {
+        self.errors.push(ResolveError { span, kind });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    /*
+     * The line, column and kind of every error in `source`.
+     */
+    fn errors(source: &str) -> Vec<(u32, u32, ResolveErrorKind)> {
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        Resolver::new()
+            .resolve(&program)
+            .expect_err("expected resolve errors")
+            .into_iter()
+            .map(|err| (err.span.line, err.span.column, err.kind))
+            .collect()
+    }
+
+    fn name(s: &str) -> String {
+        s.to_string()
+    }
+
+    #[test]
+    fn valid_program() {
+        let source = "const k = 1; var x;\n\
+                      procedure p(a); var y; begin y := a; x := y + k end;\n\
+                      function f(n); begin if n > 0 then return n * f(n - 1); f := 1 end;\n\
+                      begin call p(f(3)); ? x end.";
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        assert_eq!(Resolver::new().resolve(&program), Ok(()));
+    }
+
+    #[test]
+    fn undeclared() {
+        assert_eq!(errors("var x;\nbegin x := y; call q; ? z; ! g() end."), vec![
+            (2, 12, ResolveErrorKind::Undeclared(name("y"))),
+            (2, 20, ResolveErrorKind::Undeclared(name("q"))),
+            (2, 25, ResolveErrorKind::Undeclared(name("z"))),
+            (2, 30, ResolveErrorKind::Undeclared(name("g"))),
+        ]);
+    }
+
+    #[test]
+    fn undeclared_outside_its_block() {
+        assert_eq!(errors("procedure p; var l; l := 1;\nbegin ! l end."), vec![
+            (2, 9, ResolveErrorKind::Undeclared(name("l"))),
+        ]);
+    }
+
+    #[test]
+    fn assign_to_const() {
+        assert_eq!(errors("const k = 1;\nbegin k := 2; ? k end."), vec![
+            (2, 7, ResolveErrorKind::AssignToConst(name("k"))),
+            (2, 17, ResolveErrorKind::AssignToConst(name("k"))),
+        ]);
+    }
+
+    #[test]
+    fn call_of_non_procedure() {
+        assert_eq!(errors("var x;\ncall x."), vec![
+            (2, 6, ResolveErrorKind::CallOfNonProcedure(name("x"))),
+        ]);
+    }
+
+    #[test]
+    fn procedure_as_value() {
+        assert_eq!(errors("procedure p; ! 1;\nbegin ! p; p := 1; ! p() end."), vec![
+            (2, 9, ResolveErrorKind::ProcedureAsValue(name("p"))),
+            (2, 12, ResolveErrorKind::ProcedureAsValue(name("p"))),
+            (2, 22, ResolveErrorKind::ProcedureAsValue(name("p"))),
+        ]);
+    }
+
+    #[test]
+    fn duplicate_declaration() {
+        assert_eq!(errors("const a = 1; var a, b;\nprocedure b; ! 1;\n! 1."), vec![
+            (1, 18, ResolveErrorKind::DuplicateDeclaration(name("a"))),
+            (2, 11, ResolveErrorKind::DuplicateDeclaration(name("b"))),
+        ]);
+    }
+
+    #[test]
+    fn shadowing_is_not_a_duplicate() {
+        let source = "var a;\nprocedure p(a); var b; ! a + b;\ncall p(a).";
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        assert_eq!(Resolver::new().resolve(&program), Ok(()));
+    }
+
+    #[test]
+    fn arity_mismatch() {
+        let source = "procedure p(a, b); ! a;\nfunction f(n); f := n;\n\
+                      begin call p(1); ! f(1, 2) end.";
+        assert_eq!(errors(source), vec![
+            (3, 12, ResolveErrorKind::ArityMismatch { name: name("p"), expected: 2, found: 1 }),
+            (3, 20, ResolveErrorKind::ArityMismatch { name: name("f"), expected: 1, found: 2 }),
+        ]);
+    }
+
+    #[test]
+    fn call_of_function() {
+        assert_eq!(errors("function f; f := 1;\ncall f."), vec![
+            (2, 6, ResolveErrorKind::CallOfFunction(name("f"))),
+        ]);
+    }
+
+    #[test]
+    fn call_of_non_function() {
+        assert_eq!(errors("var x;\n! x(1)."), vec![
+            (2, 3, ResolveErrorKind::CallOfNonFunction(name("x"))),
+        ]);
+    }
+
+    #[test]
+    fn function_as_value() {
+        assert_eq!(errors("function f; f := 1;\n! f + 1."), vec![
+            (2, 3, ResolveErrorKind::FunctionAsValue(name("f"))),
+        ]);
+    }
+
+    #[test]
+    fn assign_to_function() {
+        let source = "function f; f := 1;\nprocedure p; f := 2;\nbegin f := 3; ? f end.";
+        assert_eq!(errors(source), vec![
+            (2, 14, ResolveErrorKind::AssignToFunction(name("f"))),
+            (3, 7, ResolveErrorKind::AssignToFunction(name("f"))),
+            (3, 17, ResolveErrorKind::AssignToFunction(name("f"))),
+        ]);
+    }
+
+    #[test]
+    fn return_outside_function() {
+        assert_eq!(errors("procedure p; return 1;\nreturn 2."), vec![
+            (1, 14, ResolveErrorKind::ReturnOutsideFunction),
+            (2, 1, ResolveErrorKind::ReturnOutsideFunction),
+        ]);
+    }
+}
@@ -1,14 +1,46 @@
 use std::collections::HashMap;
+use std::fmt;
 
-use crate::ast::{Literal, Token, Type};
+use crate::ast::{Literal, Span, Token, Type};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexErrorKind {
+    UnknownCharacter(char),
+    BareColon,
+    IntegerOverflow(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexError {
+    pub span: Span,
+    pub kind: LexErrorKind,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            LexErrorKind::UnknownCharacter(c) =>
+                write!(f, "{}: unknown character '{}'", self.span, c),
+            LexErrorKind::BareColon =>
+                write!(f, "{}: expected '=' after ':'", self.span),
+            LexErrorKind::IntegerOverflow(digits) =>
+                write!(f, "{}: integer {} does not fit in 32 bits",
+                       self.span, digits),
+        }
+    }
+}
 
 pub struct Scanner {
     source: Vec<char>,
-    pub tokens: Vec<Token>,
+    tokens: Vec<Token>,
+    errors: Vec<LexError>,
     reserved: HashMap<&'static str, Type>,
     start: usize,
     current: usize,
+    start_offset: usize,
+    offset: usize,
     line: u32,
+    line_start: usize,
 }
 
 impl Scanner {
@@ -18,10 +50,13 @@ impl Scanner {
         reserved.insert("call", Type::Call);
         reserved.insert("const", Type::Const);
         reserved.insert("do", Type::Do);
+        reserved.insert("else", Type::Else);
         reserved.insert("end", Type::End);
+        reserved.insert("function", Type::Function);
         reserved.insert("if", Type::If);
         reserved.insert("odd", Type::Odd);
         reserved.insert("procedure", Type::Procedure);
+        reserved.insert("return", Type::Return);
         reserved.insert("then", Type::Then);
         reserved.insert("var", Type::Var);
         reserved.insert("while", Type::While);
@@ -29,20 +64,33 @@ impl Scanner {
         Scanner {
             source,
             tokens: Vec::new(),
+            errors: Vec::new(),
             reserved,
             start: 0,
             current: 0,
-            line: 1
+            start_offset: 0,
+            offset: 0,
+            line: 1,
+            line_start: 0,
         }
     }
 
-    pub fn scan_tokens(&mut self) {
+    pub fn scan_tokens(&mut self) -> Result<Vec<Token>, Vec<LexError>> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_offset = self.offset;
             self.scan_token();
         }
 
+        self.start = self.current;
+        self.start_offset = self.offset;
         self.add_token(Type::EOF);
+
+        if self.errors.is_empty() {
+            Ok(std::mem::take(&mut self.tokens))
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
     }
 
     fn scan_token(&mut self) {
@@ -77,6 +125,8 @@ impl Scanner {
             ':' => {
                 if self.match_char('=') {
                     self.add_token(Type::ColonEqual);
+                } else {
+                    self.error(LexErrorKind::BareColon);
                 }
             },
             '/' => {
@@ -88,12 +138,10 @@ impl Scanner {
                     self.add_token(Type::Slash);
                 }
             },
-            ' ' => return,
-            '\t' => return,
-            '\r' => return,
+            ' ' | '\t' | '\r' => (),
             '\n' => {
                 self.line += 1;
-                return;
+                self.line_start = self.current;
             },
             _   => {
                 if Scanner::is_digit(c) {
@@ -101,7 +149,7 @@ impl Scanner {
                 } else if Scanner::is_alpha(c) {
                     self.identifier();
                 } else {
-                    self.error("Unknown character");
+                    self.error(LexErrorKind::UnknownCharacter(c));
                 }
             },
         }
@@ -114,13 +162,23 @@ impl Scanner {
     fn add_literal_token(&mut self, token: Type, literal: Option<Literal>) {
         let lexeme = self.source[self.start..self.current].to_vec();
         let lexeme = lexeme.iter().collect();
-        let token = Token::new(token, lexeme, literal, self.line);
+        let token = Token::new(token, lexeme, literal, self.span());
         self.tokens.push(token);
     }
 
+    fn span(&self) -> Span {
+        let column = (self.start - self.line_start) as u32 + 1;
+        Span::new(self.start_offset,
+                  self.offset - self.start_offset,
+                  self.line,
+                  column)
+    }
+
     fn advance(&mut self) -> char {
+        let c = self.source[self.current];
         self.current += 1;
-        self.source[self.current - 1]
+        self.offset += c.len_utf8();
+        c
     }
 
     fn is_at_end(&self) -> bool {
@@ -136,7 +194,7 @@ impl Scanner {
             return false;
         }
 
-        self.current += 1;
+        self.advance();
         true
     }
 
@@ -149,13 +207,11 @@ impl Scanner {
     }
 
     fn is_digit(c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
 
     fn is_alpha(c: char) -> bool {
-        (c >= 'a' && c <= 'z') ||
-        (c >= 'A' && c <= 'Z') ||
-        c == '_'
+        c.is_ascii_alphabetic() || c == '_'
     }
 
     fn is_alpha_numeric(c: char) -> bool {
@@ -172,7 +228,7 @@ impl Scanner {
         let digit: i32 = match slice.parse() {
             Ok(d) => d,
             Err(_) => {
-                self.error("Failed to parse digit");
+                self.error(LexErrorKind::IntegerOverflow(slice));
                 0
             }
         };
@@ -193,8 +249,89 @@ impl Scanner {
         }
     }
 
-    fn error(&self, message: &str) {
-        println!("Error at line {}, {}, character {}",
-            self.line, message, self.source[self.current - 1]);
+    fn error(&mut self, kind: LexErrorKind) {
+        let span = self.span();
+        self.errors.push(LexError { span, kind });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(source: &str) -> Result<Vec<Token>, Vec<LexError>> {
+        Scanner::new(source.chars().collect()).scan_tokens()
+    }
+
+    fn errors(source: &str) -> Vec<LexError> {
+        scan(source).expect_err("expected lexical errors")
+    }
+
+    #[test]
+    fn unknown_character() {
+        let errs = errors("var x;\nx := 1 $ 2");
+        assert_eq!(errs, vec![LexError {
+            span: Span::new(14, 1, 2, 8),
+            kind: LexErrorKind::UnknownCharacter('$'),
+        }]);
+    }
+
+    #[test]
+    fn bare_colon() {
+        let errs = errors("x : 1");
+        assert_eq!(errs, vec![LexError {
+            span: Span::new(2, 1, 1, 3),
+            kind: LexErrorKind::BareColon,
+        }]);
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let errs = errors("! 2147483647; ! 99999999999");
+        assert_eq!(errs, vec![LexError {
+            span: Span::new(16, 11, 1, 17),
+            kind: LexErrorKind::IntegerOverflow("99999999999".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let kinds: Vec<LexErrorKind> = errors("$ x : 1;\n! 99999999999 @")
+            .into_iter()
+            .map(|err| err.kind)
+            .collect();
+        assert_eq!(kinds, vec![
+            LexErrorKind::UnknownCharacter('$'),
+            LexErrorKind::BareColon,
+            LexErrorKind::IntegerOverflow("99999999999".to_string()),
+            LexErrorKind::UnknownCharacter('@'),
+        ]);
+    }
+
+    #[test]
+    fn token_spans() {
+        let tokens = scan("var x;\n  beta := 12").unwrap();
+        let spans: Vec<(Type, Span)> = tokens.iter().map(|t| (t.r#type, t.span)).collect();
+        assert_eq!(spans, vec![
+            (Type::Var, Span::new(0, 3, 1, 1)),
+            (Type::Identifier, Span::new(4, 1, 1, 5)),
+            (Type::Semicolon, Span::new(5, 1, 1, 6)),
+            (Type::Identifier, Span::new(9, 4, 2, 3)),
+            (Type::ColonEqual, Span::new(14, 2, 2, 8)),
+            (Type::Number, Span::new(17, 2, 2, 11)),
+            (Type::EOF, Span::new(19, 0, 2, 13)),
+        ]);
+    }
+
+    #[test]
+    fn offsets_count_bytes_and_columns_count_characters() {
+        let errs = errors("// é\nx é");
+        assert_eq!(errs, vec![LexError {
+            span: Span::new(8, 2, 2, 3),
+            kind: LexErrorKind::UnknownCharacter('é'),
+        }]);
+
+        let tokens = scan("// é\nx").unwrap();
+        assert_eq!(tokens[0].span, Span::new(6, 1, 2, 1));
     }
 }
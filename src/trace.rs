@@ -0,0 +1,204 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::codegen::CodeGen;
+use crate::disasm::{self, Labels};
+use crate::ir::Label;
+use crate::vm::{TraceHook, Traced};
+
+/*
+ * Which instructions to trace.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    All,
+    // Addresses from the first to the last, inclusive.
+    Addresses(u32, u32),
+    // The body of every routine with this name, but not what it calls.
+    Procedure(String),
+}
+
+impl Filter {
+    /*
+     * Parse "<from>-<to>" with hex addresses, or a procedure name.
+     */
+    pub fn parse(spec: &str) -> Option<Filter> {
+        if let Some((from, to)) = spec.split_once('-') {
+            let from = u32::from_str_radix(from, 16).ok()?;
+            let to = u32::from_str_radix(to, 16).ok()?;
+            return Some(Filter::Addresses(from, to));
+        }
+        if spec.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !spec.is_empty() {
+            return Some(Filter::Procedure(spec.to_string()));
+        }
+        None
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::All => write!(f, "all"),
+            Filter::Addresses(from, to) => write!(f, "{:x}-{:x}", from, to),
+            Filter::Procedure(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/*
+ * Prints executed instructions to stderr with the operand stack before
+ * and after each one:
+ *
+ *   0006  LOADC   2                [] -> [2]
+ *   0007  STOREL  1,0              [2] -> []
+ */
+pub struct Tracer {
+    image: Vec<u32>,
+    labels: Labels,
+    // Code to trace. Everything is traced when it is None.
+    ranges: Option<Vec<Range<u32>>>,
+}
+
+impl Tracer {
+    /*
+     * Trace the program generated by `codegen`. `routines` are the entry
+     * labels reported by `IRGen`, used to find a procedure's code. Returns
+     * None when the filter names a procedure that does not exist.
+     */
+    pub fn new(codegen: &CodeGen, routines: &[(String, Label)], filter: &Filter) -> Option<Tracer> {
+        let ranges = match filter {
+            Filter::All => None,
+            Filter::Addresses(from, to) => {
+                let range = *from..to.saturating_add(1);
+                Some(vec![range])
+            },
+            Filter::Procedure(name) => {
+                let ranges = Tracer::procedure(codegen, routines, name);
+                if ranges.is_empty() {
+                    return None;
+                }
+                Some(ranges)
+            },
+        };
+
+        Some(Tracer {
+            image: codegen.output.clone(),
+            labels: disasm::labels(&codegen.object().symbols),
+            ranges,
+        })
+    }
+
+    /*
+     * The code of each routine called `name`. Routine bodies are laid out
+     * one after another after the main program, so each one ends where
+     * the next begins.
+     */
+    fn procedure(codegen: &CodeGen, routines: &[(String, Label)], name: &str) -> Vec<Range<u32>> {
+        let mut starts: Vec<u32> = routines.iter()
+            .filter_map(|(_, label)| codegen.symbol_table.get(label).copied())
+            .collect();
+        starts.push(codegen.code_len);
+        starts.sort_unstable();
+
+        routines.iter()
+            .filter(|(routine, _)| routine == name)
+            .filter_map(|(_, label)| codegen.symbol_table.get(label).copied())
+            .map(|start| {
+                let end = starts.iter().copied().find(|&s| s > start).unwrap_or(start);
+                start..end
+            })
+            .collect()
+    }
+
+    fn wanted(&self, pc: u32) -> bool {
+        match &self.ranges {
+            None => true,
+            Some(ranges) => ranges.iter().any(|range| range.contains(&pc)),
+        }
+    }
+
+    pub fn trace(&self, traced: &Traced) {
+        if !self.wanted(traced.pc) || traced.pc as usize >= self.image.len() {
+            return;
+        }
+        let (text, _) = disasm::decode(&self.image, traced.pc as usize, &self.labels);
+        eprintln!("{:04x}  {:<24} {:?} -> {:?}", traced.pc, text, traced.before, traced.after);
+    }
+
+    pub fn hook(self) -> TraceHook {
+        Box::new(move |traced| self.trace(traced))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irgen::IRGen;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> (IRGen, CodeGen) {
+        let tokens = Scanner::new(source.chars().collect()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        Resolver::new().resolve(&program).unwrap();
+        let mut irgen = IRGen::new();
+        irgen.gen(program);
+        let mut codegen = CodeGen::new();
+        codegen.gen(&mut irgen.code).unwrap();
+        (irgen, codegen)
+    }
+
+    fn start(codegen: &CodeGen, irgen: &IRGen, name: &str) -> u32 {
+        let (_, label) = irgen.routines.iter().find(|(routine, _)| routine == name).unwrap();
+        codegen.symbol_table[label]
+    }
+
+    #[test]
+    fn address_range() {
+        assert_eq!(Filter::parse("10-2f"), Some(Filter::Addresses(0x10, 0x2f)));
+        assert_eq!(Filter::parse("10-2f").unwrap().to_string(), "10-2f");
+    }
+
+    #[test]
+    fn bad_hex() {
+        assert_eq!(Filter::parse("10-zz"), None);
+        assert_eq!(Filter::parse("-2f"), None);
+        assert_eq!(Filter::parse("1-2-3"), None);
+    }
+
+    #[test]
+    fn procedure_name() {
+        assert_eq!(Filter::parse("is_prime2"), Some(Filter::Procedure("is_prime2".to_string())));
+        assert_eq!(Filter::parse(""), None);
+        assert_eq!(Filter::parse("a b"), None);
+    }
+
+    #[test]
+    fn procedure_range_leaves_out_nested_routines() {
+        let (irgen, codegen) = compile("var x;\n\
+                                        procedure p;\n\
+                                        var y;\n  \
+                                          procedure q; begin y := 1 end;\n\
+                                        begin call q; x := y end;\n\
+                                        procedure r; ! x;\n\
+                                        begin call p; call r end.");
+        let p = start(&codegen, &irgen, "p");
+        let q = start(&codegen, &irgen, "q");
+        let r = start(&codegen, &irgen, "r");
+        // A nested routine is laid out before its parent.
+        assert!(q < p && p < r);
+
+        assert_eq!(Tracer::procedure(&codegen, &irgen.routines, "q"), vec![q..p]);
+        assert_eq!(Tracer::procedure(&codegen, &irgen.routines, "p"), vec![p..r]);
+        assert_eq!(Tracer::procedure(&codegen, &irgen.routines, "r"), vec![r..codegen.code_len]);
+        assert!(Tracer::procedure(&codegen, &irgen.routines, "s").is_empty());
+    }
+
+    #[test]
+    fn unknown_procedure() {
+        let (irgen, codegen) = compile("procedure p; ! 1; call p.");
+        assert!(Tracer::new(&codegen, &irgen.routines, &Filter::Procedure("q".to_string())).is_none());
+        assert!(Tracer::new(&codegen, &irgen.routines, &Filter::Procedure("p".to_string())).is_some());
+    }
+}
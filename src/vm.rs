@@ -1,12 +1,235 @@
-use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read};
 
-pub struct VM {
+use crate::codegen::{LineTable, Object, OBJECT_MAGIC, OBJECT_VERSION};
+use crate::interp::MAX_CALL_DEPTH;
+use crate::io::{InputError, Io, StdIo};
+use crate::opcode;
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    BadSymbol,
+    // Code and data together, in words.
+    TooLarge(u64),
+    BadEntry(u32),
+    SymbolTooLong(u32),
+}
+
+// Longest symbol name accepted in an object file, in bytes.
+pub const MAX_SYMBOL_LEN: u32 = 1024;
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectError::Io(err) => write!(f, "{}", err),
+            ObjectError::BadMagic => write!(f, "not a lozenge object file"),
+            ObjectError::UnsupportedVersion(v) =>
+                write!(f, "unsupported object file version {}", v),
+            ObjectError::BadSymbol => write!(f, "malformed symbol table"),
+            ObjectError::TooLarge(len) =>
+                write!(f, "program of {} words does not fit in memory", len),
+            ObjectError::BadEntry(entry) =>
+                write!(f, "entry point {:04x} is outside the code", entry),
+            ObjectError::SymbolTooLong(len) =>
+                write!(f, "symbol name of {} bytes is too long", len),
+        }
+    }
+}
+
+impl From<io::Error> for ObjectError {
+    fn from(err: io::Error) -> ObjectError {
+        ObjectError::Io(err)
+    }
+}
+
+/*
+ * Read an object file in the format written by `Object::write`, or in
+ * an older version of it.
+ */
+pub fn read_object<R: Read>(r: &mut R) -> Result<Object, ObjectError> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if magic != OBJECT_MAGIC {
+        return Err(ObjectError::BadMagic);
+    }
+
+    let version = read_word(r)?;
+    if version == 0 || version > OBJECT_VERSION {
+        return Err(ObjectError::UnsupportedVersion(version));
+    }
+
+    let entry = read_word(r)?;
+    let code_len = read_word(r)?;
+    let data_len = read_word(r)?;
+    let symbol_count = read_word(r)?;
+
+    // Check the header before trusting it with any allocation.
+    let len = code_len as u64 + data_len as u64;
+    if len > opcode::ADDRESS_SPACE as u64 {
+        return Err(ObjectError::TooLarge(len));
+    }
+    if entry >= code_len {
+        return Err(ObjectError::BadEntry(entry));
+    }
+
+    let code = read_words(r, code_len)?;
+    let data = read_words(r, data_len)?;
+
+    let mut symbols = Vec::new();
+    for _ in 0..symbol_count {
+        let address = read_word(r)?;
+        let len = read_word(r)?;
+        if len > MAX_SYMBOL_LEN {
+            return Err(ObjectError::SymbolTooLong(len));
+        }
+        let len = len as usize;
+        let mut name = vec![0; len + (4 - len % 4) % 4];
+        r.read_exact(&mut name)?;
+        name.truncate(len);
+        let name = String::from_utf8(name).map_err(|_| ObjectError::BadSymbol)?;
+        symbols.push((name, address));
+    }
+
+    let mut lines = LineTable::default();
+    if version >= 2 {
+        let count = read_word(r)?;
+        for _ in 0..count {
+            let address = read_word(r)?;
+            let line = read_word(r)?;
+            lines.entries.push((address, line));
+        }
+    }
+
+    Ok(Object { entry, code, data, symbols, lines })
+}
+
+fn read_word<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut word = [0; 4];
+    r.read_exact(&mut word)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+fn read_words<R: Read>(r: &mut R, len: u32) -> io::Result<Vec<u32>> {
+    (0..len).map(|_| read_word(r)).collect()
+}
+
+// Words of memory a VM starts with.
+pub const MEMORY_SIZE: usize = 2048;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitStatus {
+    Halted,
+}
+
+/*
+ * A fault raised while running a program. Every variant carries the
+ * address of the instruction that caused it.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    StackUnderflow { pc: u32 },
+    DivisionByZero { pc: u32 },
+    MemoryOutOfBounds { pc: u32, addr: u32 },
+    InvalidOpcode { pc: u32, word: u32 },
+    ReturnStackUnderflow { pc: u32 },
+    InvalidFrame { pc: u32, depth: usize, offset: usize },
+    EndOfInput { pc: u32 },
+    InvalidInput { pc: u32, text: String },
+    BudgetExhausted { pc: u32 },
+    CallTooDeep { pc: u32 },
+}
+
+impl VmError {
+    pub fn pc(&self) -> u32 {
+        match self {
+            VmError::StackUnderflow { pc }
+            | VmError::DivisionByZero { pc }
+            | VmError::MemoryOutOfBounds { pc, .. }
+            | VmError::InvalidOpcode { pc, .. }
+            | VmError::ReturnStackUnderflow { pc }
+            | VmError::InvalidFrame { pc, .. }
+            | VmError::EndOfInput { pc }
+            | VmError::InvalidInput { pc, .. }
+            | VmError::BudgetExhausted { pc }
+            | VmError::CallTooDeep { pc } => *pc,
+        }
+    }
+
+    /*
+     * What went wrong, without the address.
+     */
+    pub fn message(&self) -> String {
+        match self {
+            VmError::StackUnderflow { .. } => "stack underflow".to_string(),
+            VmError::DivisionByZero { .. } => "division by zero".to_string(),
+            VmError::MemoryOutOfBounds { addr, .. } =>
+                format!("address {:04x} is out of bounds", addr),
+            VmError::InvalidOpcode { word, .. } =>
+                format!("invalid instruction {:08x}", word),
+            VmError::ReturnStackUnderflow { .. } =>
+                "return without a call".to_string(),
+            VmError::InvalidFrame { depth, offset, .. } =>
+                format!("no local {} in a frame at depth {}", offset, depth),
+            VmError::EndOfInput { .. } => "unexpected end of input".to_string(),
+            VmError::InvalidInput { text, .. } =>
+                format!("expected a number, got '{}'", text),
+            VmError::BudgetExhausted { .. } => "step budget exhausted".to_string(),
+            VmError::CallTooDeep { .. } =>
+                format!("calls nested more than {} deep", MAX_CALL_DEPTH),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}: {}", self.pc(), self.message())
+    }
+}
+
+/*
+ * Activation record of a procedure call. `saved` is the display entry
+ * this frame replaced, restored when the procedure returns.
+ */
+struct Frame {
+    locals: Vec<i32>,
+    depth: usize,
+    saved: Option<usize>,
+}
+
+/*
+ * An executed instruction as reported to a trace hook, with the operand
+ * stack before and after it ran.
+ */
+pub struct Traced<'a> {
+    pub pc: u32,
+    pub before: &'a [i32],
+    pub after: &'a [i32],
+}
+
+pub type TraceHook = Box<dyn FnMut(&Traced)>;
+
+pub struct VM<I: Io = StdIo> {
     pc: u32,
     mar: u32,
     stack: Vec<i32>,
     return_stack: Vec<u32>,
+    // Number of frames when each return address was pushed. RET drops
+    // the frames the routine entered, if any.
+    frame_marks: Vec<usize>,
+    frames: Vec<Frame>,
+    // Index into `frames` of the newest activation at each static depth.
+    display: Vec<Option<usize>>,
     memory: Vec<i32>,
     state: State,
+    io: I,
+    // Called after every instruction when set. Without one, nothing is
+    // copied or checked beyond this field.
+    trace: Option<TraceHook>,
+    // Instructions left to execute before giving up, if limited.
+    budget: Option<u64>,
 }
 
 #[derive(PartialEq)]
@@ -23,183 +246,421 @@ impl Default for VM {
 
 impl VM {
     pub fn new() -> VM {
+        VM::with_io(StdIo::new())
+    }
+}
+
+impl<I: Io> VM<I> {
+    /*
+     * Create a VM whose WRITE and READ instructions go through `io`.
+     */
+    pub fn with_io(io: I) -> VM<I> {
         VM {
             pc: 0,
             mar: 0,
             stack: Vec::new(),
             return_stack: Vec::new(),
-            memory: vec![0; 2048],
-            state: State::Running
+            frame_marks: Vec::new(),
+            frames: Vec::new(),
+            display: Vec::new(),
+            memory: vec![0; MEMORY_SIZE],
+            state: State::Running,
+            io,
+            trace: None,
+            budget: None,
         }
     }
 
+    pub fn io(&self) -> &I {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    /*
+     * Limit the number of instructions later steps may execute, so a
+     * program that never halts fails instead.
+     */
+    pub fn set_budget(&mut self, steps: Option<u64>) {
+        self.budget = steps;
+    }
+
+    pub fn set_trace(&mut self, hook: Option<TraceHook>) {
+        self.trace = hook;
+    }
+
+    /*
+     * Copy a program image to the start of memory, which grows to hold
+     * images larger than the default size.
+     */
     pub fn load(&mut self, program: &[u32]) {
+        if program.len() > self.memory.len() {
+            self.memory.resize(program.len(), 0);
+        }
         for (i, n) in program.iter().enumerate() {
             self.memory[i] = (*n) as i32;
         }
     }
 
-    pub fn run(&mut self) {
+    /*
+     * Load an object's code with its data directly after it and start
+     * execution at its entry point.
+     */
+    pub fn load_object(&mut self, object: &Object) {
+        let program: Vec<u32> = object.code.iter()
+            .chain(object.data.iter())
+            .copied()
+            .collect();
+        self.load(&program);
+        self.pc = object.entry;
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn return_stack(&self) -> &[u32] {
+        &self.return_stack
+    }
+
+    pub fn memory(&self) -> &[i32] {
+        &self.memory
+    }
+
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
         loop {
-            if self.state == State::Halt {
-                break;
+            if let Some(status) = self.step()? {
+                return Ok(status);
             }
+        }
+    }
 
-            let cell: u32 = self.memory[self.pc as usize] as u32;
-            self.pc += 1;
-
-            let instruction = (cell & 0xFF00_0000) >> 24;
-            match instruction {
-                // JMP
-                0x10 => {
-                    let address = cell & 0x00FF_FFFF;
-                    self.pc = address.try_into().unwrap();
-                },
-                // JMZ
-                0x20 => {
-                    let address = cell & 0x00FF_FFFF;
-                    let val = self.stack.pop().unwrap();
-                    if val == 0 {
-                        self.pc = address.try_into().unwrap();
-                    }
-                },
-                // LOAD
-                0x30 => {
-                    let address = cell & 0x00FF_FFFF;
-                    self.mar = address.try_into().unwrap();
-                    self.stack.push(self.memory[self.mar as usize] as i32);
-                },
-                // LOADC
-                0x40 => {
-                    let value = cell & 0x00FF_FFFF;
-                    self.stack.push(value as i32);
-                },
-                // STORE
-                0x50 => {
-                    let address = cell & 0x00FF_FFFF;
-                    self.mar = address.try_into().unwrap();
-                    let value = self.stack.pop().unwrap();
-                    self.memory[self.mar as usize] = value;
-                },
-                // CALL
-                0x60 => {
-                    let address = cell & 0x00FF_FFFF;
-                    self.return_stack.push(self.pc);
-                    self.pc = address.try_into().unwrap();
-                },
-                // WRITE
-                0x70 => {
-                    let value = self.stack.pop().unwrap();
-                    println!("{}", value);
-                },
-                // ADD
-                0x80 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(a + b);
-                },
-                // SUB
-                0x90 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(b - a);
-                },
-                // DIV
-                0xA0 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(b / a);
-                },
-                // MUL
-                0xB0 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(a * b);
-                },
-                // ODD
-                0xC0 => {
-                    let a = self.stack.pop().unwrap();
-                    if (a % 2) == 1 {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // LT
-                0xD0 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    if b < a {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // LTE
-                0xE0 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    if b <= a {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // GT
-                0xF0 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    if b > a {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // GTE
-                0xF1 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    if b >= a {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // EQ
-                0xF2 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    if b == a {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // NEQ
-                0xF3 => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    if a != b {
-                        self.stack.push(1);
-                    } else {
-                        self.stack.push(0);
-                    }
-                },
-                // NOOP
-                0xF4 => {
-                    continue;
-                },
-                // RET
-                0xF5 => {
-                    let address = self.return_stack.pop().unwrap();
-                    self.pc = address;
-                },
-                // HALT
-                0xF6 => {
-                    self.state = State::Halt;
-                },
-                _ => (),
+    /*
+     * Execute the instruction at pc, returning the exit status once the
+     * program has stopped. Stepping a stopped program does nothing.
+     */
+    pub fn step(&mut self) -> Result<Option<ExitStatus>, VmError> {
+        if self.state == State::Running {
+            if let Some(budget) = self.budget.as_mut() {
+                if *budget == 0 {
+                    return Err(VmError::BudgetExhausted { pc: self.pc });
+                }
+                *budget -= 1;
             }
+            match self.trace {
+                None => self.execute()?,
+                Some(_) => self.execute_traced()?,
+            }
+        }
+        match self.state {
+            State::Running => Ok(None),
+            State::Halt => Ok(Some(ExitStatus::Halted)),
+        }
+    }
+
+    /*
+     * Execute the instruction at pc and report it to the trace hook, even
+     * when it fails.
+     */
+    fn execute_traced(&mut self) -> Result<(), VmError> {
+        let pc = self.pc;
+        let before = self.stack.clone();
+        let result = self.execute();
+        if let Some(hook) = self.trace.as_mut() {
+            hook(&Traced { pc, before: &before, after: &self.stack });
+        }
+        result
+    }
+
+    /*
+     * Fetch and execute the instruction at pc.
+     */
+    fn execute(&mut self) -> Result<(), VmError> {
+        let pc = self.pc;
+        let cell = self.fetch(pc, pc)? as u32;
+        self.pc += 1;
+
+        match opcode::opcode(cell) {
+            opcode::JMP => {
+                self.pc = opcode::address(cell);
+            },
+            opcode::JMZ => {
+                let val = self.pop(pc)?;
+                if val == 0 {
+                    self.pc = opcode::address(cell);
+                }
+            },
+            opcode::LOAD => {
+                self.mar = opcode::address(cell);
+                let value = self.fetch(self.mar, pc)?;
+                self.stack.push(value);
+            },
+            opcode::LOADL => {
+                let (depth, offset) = opcode::frame(cell);
+                let value = *self.local(depth, offset, pc)?;
+                self.stack.push(value);
+            },
+            opcode::LOADC => {
+                self.stack.push(opcode::immediate(cell));
+            },
+            opcode::LOADW => {
+                let value = self.fetch(self.pc, pc)?;
+                self.pc += 1;
+                self.stack.push(value);
+            },
+            opcode::STORE => {
+                self.mar = opcode::address(cell);
+                let value = self.pop(pc)?;
+                match self.memory.get_mut(self.mar as usize) {
+                    Some(cell) => *cell = value,
+                    None => return Err(VmError::MemoryOutOfBounds { pc, addr: self.mar }),
+                }
+            },
+            opcode::STOREL => {
+                let (depth, offset) = opcode::frame(cell);
+                let value = self.pop(pc)?;
+                *self.local(depth, offset, pc)? = value;
+            },
+            opcode::CALL => {
+                if self.return_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(VmError::CallTooDeep { pc });
+                }
+                self.return_stack.push(self.pc);
+                self.frame_marks.push(self.frames.len());
+                self.pc = opcode::address(cell);
+            },
+            opcode::WRITE => {
+                let value = self.pop(pc)?;
+                self.io.write_int(value);
+            },
+            opcode::ADD => self.binary(pc, |b, a| Some(b.wrapping_add(a)))?,
+            opcode::SUB => self.binary(pc, |b, a| Some(b.wrapping_sub(a)))?,
+            opcode::MUL => self.binary(pc, |b, a| Some(b.wrapping_mul(a)))?,
+            opcode::DIV => {
+                self.binary(pc, |b, a| if a == 0 { None } else { Some(b.wrapping_div(a)) })?
+            },
+            opcode::ODD => {
+                let a = self.pop(pc)?;
+                self.stack.push((a % 2 != 0) as i32);
+            },
+            opcode::LT => self.binary(pc, |b, a| Some((b < a) as i32))?,
+            opcode::LTE => self.binary(pc, |b, a| Some((b <= a) as i32))?,
+            opcode::GT => self.binary(pc, |b, a| Some((b > a) as i32))?,
+            opcode::GTE => self.binary(pc, |b, a| Some((b >= a) as i32))?,
+            opcode::EQ => self.binary(pc, |b, a| Some((b == a) as i32))?,
+            opcode::NEQ => self.binary(pc, |b, a| Some((b != a) as i32))?,
+            opcode::NOOP => (),
+            opcode::RET => {
+                let address = self.return_stack.pop()
+                    .ok_or(VmError::ReturnStackUnderflow { pc })?;
+                let mark = self.frame_marks.pop()
+                    .ok_or(VmError::ReturnStackUnderflow { pc })?;
+                while self.frames.len() > mark {
+                    if let Some(frame) = self.frames.pop() {
+                        self.display[frame.depth] = frame.saved;
+                    }
+                }
+                self.pc = address;
+            },
+            opcode::HALT => {
+                self.state = State::Halt;
+            },
+            opcode::READ => {
+                let value = self.read_number(pc)?;
+                self.stack.push(value);
+            },
+            opcode::ENTER => {
+                let (depth, size) = opcode::frame(cell);
+                if self.display.len() <= depth {
+                    self.display.resize(depth + 1, None);
+                }
+                self.frames.push(Frame {
+                    locals: vec![0; size],
+                    depth,
+                    saved: self.display[depth],
+                });
+                self.display[depth] = Some(self.frames.len() - 1);
+            },
+            _ => return Err(VmError::InvalidOpcode { pc, word: cell }),
+        }
+
+        Ok(())
+    }
+
+    fn pop(&mut self, pc: u32) -> Result<i32, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow { pc })
+    }
+
+    fn fetch(&self, addr: u32, pc: u32) -> Result<i32, VmError> {
+        self.memory.get(addr as usize)
+            .copied()
+            .ok_or(VmError::MemoryOutOfBounds { pc, addr })
+    }
+
+    /*
+     * Pop two operands and push `op(b, a)`, where `a` was on top of the
+     * stack. An operation returning None is a division by zero.
+     */
+    fn binary<F>(&mut self, pc: u32, op: F) -> Result<(), VmError>
+        where F: Fn(i32, i32) -> Option<i32>
+    {
+        let a = self.pop(pc)?;
+        let b = self.pop(pc)?;
+        let value = op(b, a).ok_or(VmError::DivisionByZero { pc })?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /*
+     * The local at `offset` in the newest frame at static depth `depth`.
+     */
+    fn local(&mut self, depth: usize, offset: usize, pc: u32) -> Result<&mut i32, VmError> {
+        let frames = &mut self.frames;
+        self.display.get(depth)
+            .copied()
+            .flatten()
+            .and_then(move |frame| frames[frame].locals.get_mut(offset))
+            .ok_or(VmError::InvalidFrame { pc, depth, offset })
+    }
+
+    fn read_number(&mut self, pc: u32) -> Result<i32, VmError> {
+        self.io.read_int().map_err(|err| match err {
+            InputError::EndOfInput => VmError::EndOfInput { pc },
+            InputError::Invalid(text) => VmError::InvalidInput { pc, text },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::io::BufferIo;
+
+    fn object() -> Object {
+        let codegen = asm::assemble("x: DEC 7\nLOAD x\nLOADC 100000000\nADD\nWRITE\nHALT").unwrap();
+        codegen.object()
+    }
+
+    fn bytes(object: &Object) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        object.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /*
+     * Overwrite the header word at `index`, counting the version as 0.
+     */
+    fn patch(bytes: &mut [u8], index: usize, word: u32) {
+        let at = 4 + index * 4;
+        bytes[at..at + 4].copy_from_slice(&word.to_le_bytes());
+    }
+
+    #[test]
+    fn object_round_trip() {
+        let object = object();
+        assert!(!object.symbols.is_empty());
+        let read = read_object(&mut bytes(&object).as_slice()).unwrap();
+        assert_eq!(read, object);
+    }
+
+    #[test]
+    fn object_round_trip_with_line_table() {
+        let mut object = object();
+        object.lines.add(0, 3);
+        object.lines.add(2, 4);
+        let read = read_object(&mut bytes(&object).as_slice()).unwrap();
+        assert_eq!(read, object);
+    }
+
+    #[test]
+    fn version_1_has_no_line_table() {
+        let object = object();
+        let mut v1 = bytes(&Object { lines: LineTable::default(), ..object.clone() });
+        patch(&mut v1, 0, 1);
+        // Drop the empty line table's entry count.
+        v1.truncate(v1.len() - 4);
+
+        let read = read_object(&mut v1.as_slice()).unwrap();
+        assert_eq!(read, Object { lines: LineTable::default(), ..object });
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = bytes(&object());
+        bytes[0] = b'X';
+        assert!(matches!(read_object(&mut bytes.as_slice()), Err(ObjectError::BadMagic)));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        for version in [0, OBJECT_VERSION + 1] {
+            let mut bytes = bytes(&object());
+            patch(&mut bytes, 0, version);
+            assert!(matches!(read_object(&mut bytes.as_slice()),
+                             Err(ObjectError::UnsupportedVersion(v)) if v == version));
         }
     }
+
+    #[test]
+    fn header_is_checked_before_allocating() {
+        let object = object();
+
+        let mut image = bytes(&object);
+        patch(&mut image, 2, opcode::ADDRESS_SPACE);
+        assert!(matches!(read_object(&mut image.as_slice()), Err(ObjectError::TooLarge(_))));
+
+        let mut image = bytes(&object);
+        patch(&mut image, 1, 100);
+        assert!(matches!(read_object(&mut image.as_slice()), Err(ObjectError::BadEntry(100))));
+
+        // The first symbol's name length follows its address.
+        let mut image = bytes(&object);
+        patch(&mut image, 6 + object.code.len() + object.data.len(), u32::MAX);
+        assert!(matches!(read_object(&mut image.as_slice()),
+                         Err(ObjectError::SymbolTooLong(u32::MAX))));
+    }
+
+    #[test]
+    fn truncated_object() {
+        let bytes = bytes(&object());
+        let short = &bytes[..bytes.len() - 2];
+        assert!(matches!(read_object(&mut &short[..]), Err(ObjectError::Io(_))));
+    }
+
+    /*
+     * Assemble and run `source`, returning its output.
+     */
+    fn run(source: &str) -> Result<String, VmError> {
+        let codegen = asm::assemble(source).unwrap();
+        let mut vm = VM::with_io(BufferIo::new(""));
+        vm.load(&codegen.output);
+        vm.run()?;
+        Ok(vm.io().output().to_string())
+    }
+
+    #[test]
+    fn call_and_ret_without_a_frame() {
+        let output = run("CALL f\nLOADC 6\nWRITE\nHALT\nf: FUNC\nLOADC 5\nWRITE\nRET").unwrap();
+        assert_eq!(output, "5\n6\n");
+    }
+
+    #[test]
+    fn ret_drops_the_frame_its_routine_entered() {
+        let source = "LOADC 1\nCALL f\nCALL g\nHALT\n\
+                      f: FUNC\nENTER 1,1\nSTOREL 1,0\nRET\n\
+                      g: FUNC\nLOADL 1,0\nWRITE\nRET";
+        assert!(matches!(run(source), Err(VmError::InvalidFrame { depth: 1, offset: 0, .. })));
+    }
+
+    #[test]
+    fn ret_without_a_call() {
+        assert!(matches!(run("RET"), Err(VmError::ReturnStackUnderflow { pc: 0 })));
+    }
 }
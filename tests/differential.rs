@@ -0,0 +1,556 @@
+/*
+ * Runs programs through both the tree-walking interpreter and the VM and
+ * fails when they disagree on output or on whether the program fails.
+ */
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use lozenge::ast::{Block, Spanned};
+use lozenge::codegen::CodeGen;
+use lozenge::interp::{self, Interp, RuntimeErrorKind, MAX_CALL_DEPTH};
+use lozenge::io::{BufferIo, ScriptedIo};
+use lozenge::irgen::IRGen;
+use lozenge::parser::Parser;
+use lozenge::resolver::Resolver;
+use lozenge::scanner::Scanner;
+use lozenge::vm::{VmError, VM};
+
+// Input fed to `?` statements, enough for every example program.
+const INPUT: &str = "5\n7\n-3\n0\n12\n";
+
+fn front_end(source: &str) -> Spanned<Block> {
+    let tokens = Scanner::new(source.chars().collect())
+        .scan_tokens()
+        .unwrap_or_else(|errs| panic!("scan failed: {:?}\n{}", errs, source));
+    let program = Parser::new(tokens)
+        .parse()
+        .unwrap_or_else(|errs| panic!("parse failed: {:?}\n{}", errs, source));
+    Resolver::new()
+        .resolve(&program)
+        .unwrap_or_else(|errs| panic!("resolve failed: {:?}\n{}", errs, source));
+    program
+}
+
+/*
+ * The output of a run and whether it ended in a runtime error.
+ */
+fn run_interp(program: Spanned<Block>, input: &str) -> (String, bool) {
+    let mut interp = Interp::with_io(BufferIo::new(input));
+    let failed = interp.eval(program).is_err();
+    (interp.io().output().to_string(), failed)
+}
+
+fn run_vm(program: Spanned<Block>, input: &str) -> (String, bool) {
+    let mut irgen = IRGen::new();
+    irgen.gen(program);
+    let mut codegen = CodeGen::new();
+    codegen.gen(&mut irgen.code).unwrap();
+
+    let mut vm = VM::with_io(BufferIo::new(input));
+    vm.load(&codegen.output);
+    let failed = vm.run().is_err();
+    (vm.io().output().to_string(), failed)
+}
+
+fn assert_same(name: &str, source: &str, input: &str) {
+    let program = front_end(source);
+    let interp = run_interp(program.clone(), input);
+    let vm = run_vm(program, input);
+    assert_eq!(interp, vm,
+               "{}: interp (left) and vm (right) disagree\n{}", name, source);
+}
+
+#[test]
+fn example_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut count = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "pas") {
+            let source = fs::read_to_string(&path).unwrap();
+            assert_same(&path.display().to_string(), &source, INPUT);
+            count += 1;
+        }
+    }
+    assert!(count > 0);
+}
+
+#[test]
+fn edge_cases() {
+    let cases = [
+        ("odd of negatives", "begin if odd -3 then ! 1; if odd -4 then ! 2; if odd 0 - 7 then ! 3 end."),
+        ("wide constants", "begin ! 2000000000; ! 0 - 8388609; ! 8388608 end."),
+        ("overflow wraps", "begin ! 2147483647 + 1; ! (0 - 2147483647 - 1) / (0 - 1) end."),
+        ("division by zero", "var x; begin ! 1; ! 5 / x; ! 2 end."),
+        ("division truncates", "begin ! 7 / (0 - 2); ! (0 - 7) / 2 end."),
+        ("end of input", "var x; begin ? x; ? x; ! x end."),
+        ("function without result", "function f; begin if 1 = 0 then f := 1 end; begin ! f() end."),
+    ];
+    for (name, source) in cases.iter() {
+        assert_same(name, source, "4\n");
+    }
+
+    let source = "function f(n); begin ? f end; begin ! f(1); ! f(2) end.";
+    assert_same("read into a function result", source, "5\n6\n");
+}
+
+#[test]
+fn programs_larger_than_default_memory() {
+    let stmts = vec!["x := x + 1"; 1000].join(";\n");
+    let source = format!("var x;\nbegin\n{};\n! x\nend.", stmts);
+    assert_same("large program", &source, "");
+}
+
+#[test]
+fn budget_stops_endless_loops() {
+    let source = "var x; begin while 1 = 1 do x := x + 1 end.";
+
+    let mut interp = Interp::with_io(BufferIo::new(""));
+    interp.set_budget(Some(1000));
+    let err = interp.eval(front_end(source)).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::BudgetExhausted);
+
+    let mut irgen = IRGen::new();
+    irgen.gen(front_end(source));
+    let mut codegen = CodeGen::new();
+    codegen.gen(&mut irgen.code).unwrap();
+    let mut vm = VM::with_io(BufferIo::new(""));
+    vm.load(&codegen.output);
+    vm.set_budget(Some(1000));
+    assert!(matches!(vm.run(), Err(VmError::BudgetExhausted { .. })));
+}
+
+#[test]
+fn scripted_input_and_output() {
+    let source = "var a, b; begin ? a; ? b; ! a * b; ! a - b; ? a end.";
+
+    let mut interp = Interp::with_io(ScriptedIo::new(&[6, -7]));
+    let err = interp.eval(front_end(source)).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::EndOfInput);
+    assert_eq!(interp.io().output(), [-42, 13]);
+
+    let mut irgen = IRGen::new();
+    irgen.gen(front_end(source));
+    let mut codegen = CodeGen::new();
+    codegen.gen(&mut irgen.code).unwrap();
+    let mut vm = VM::with_io(ScriptedIo::new(&[6, -7]));
+    vm.load(&codegen.output);
+    assert!(matches!(vm.run(), Err(VmError::EndOfInput { .. })));
+    assert_eq!(vm.io().output(), [-42, 13]);
+}
+
+/*
+ * Run `f` with as much stack as the interpreter needs to reach its call
+ * depth limit, which is more than a test thread has.
+ */
+fn with_interp_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    thread::Builder::new()
+        .stack_size(interp::STACK_SIZE)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn call_depth_stops_endless_recursion() {
+    let source = "procedure p; begin call p end; call p.";
+
+    let err = with_interp_stack(move || {
+        let mut interp = Interp::with_io(BufferIo::new(""));
+        interp.eval(front_end(source)).unwrap_err()
+    });
+    assert_eq!(err.kind, RuntimeErrorKind::CallTooDeep);
+    assert_eq!(err.trace.len(), MAX_CALL_DEPTH);
+
+    let mut irgen = IRGen::new();
+    irgen.gen(front_end(source));
+    let mut codegen = CodeGen::new();
+    codegen.gen(&mut irgen.code).unwrap();
+    let mut vm = VM::with_io(BufferIo::new(""));
+    vm.load(&codegen.output);
+    assert!(matches!(vm.run(), Err(VmError::CallTooDeep { .. })));
+    assert_eq!(vm.return_stack().len(), MAX_CALL_DEPTH);
+}
+
+#[test]
+fn call_depth_limit_is_the_same_on_both_backends() {
+    for (depth, fails) in [(MAX_CALL_DEPTH, false), (MAX_CALL_DEPTH + 1, true)] {
+        let source = format!("function f(n); begin if n > 1 then f := f(n - 1) + 1 else f := 1 end;\n\
+                              begin ! f({}) end.", depth);
+        let failed = with_interp_stack(move || {
+            assert_same(&format!("depth {}", depth), &source, "");
+            run_vm(front_end(&source), "").1
+        });
+        assert_eq!(failed, fails, "depth {}", depth);
+    }
+}
+
+#[test]
+fn generated_programs() {
+    for seed in 1..=300 {
+        let source = Generator::new(seed).program();
+        assert_same(&format!("seed {}", seed), &source, INPUT);
+    }
+}
+
+/*
+ * Builds random programs that are valid and always terminate: loops only
+ * appear in the main program and count a dedicated variable up to a small
+ * bound, and routines only call routines declared before them or nested
+ * in them. A recursive routine takes a counter as its last parameter and
+ * only calls itself while the counter is positive, passing it on one
+ * less.
+ */
+struct Generator {
+    state: u64,
+    // Variables an expression may read and a statement may assign.
+    vars: Vec<String>,
+    consts: Vec<String>,
+    // Routines callable from the code being generated.
+    functions: Vec<Routine>,
+    procedures: Vec<Routine>,
+    // The function whose body is being generated.
+    function: Option<String>,
+    // The recursive routine whose body is being generated.
+    recursion: Option<Routine>,
+    // Whether a procedure or function body is being generated.
+    routine: bool,
+    // How many routines enclose the code being generated.
+    level: usize,
+    loops: usize,
+}
+
+#[derive(Clone)]
+struct Routine {
+    name: String,
+    // Parameters, not counting the counter of a recursive routine.
+    arity: usize,
+    counter: Option<String>,
+}
+
+/*
+ * The names visible outside a routine, restored once its declaration has
+ * been generated.
+ */
+struct Scope {
+    vars: Vec<String>,
+    functions: Vec<Routine>,
+    procedures: Vec<Routine>,
+    function: Option<String>,
+    recursion: Option<Routine>,
+    routine: bool,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        Generator {
+            state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            vars: Vec::new(),
+            consts: Vec::new(),
+            functions: Vec::new(),
+            procedures: Vec::new(),
+            function: None,
+            recursion: None,
+            routine: false,
+            level: 0,
+            loops: 0,
+        }
+    }
+
+    // xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick(&mut self, items: &[String]) -> String {
+        items[self.below(items.len())].clone()
+    }
+
+    fn program(&mut self) -> String {
+        let mut out = String::new();
+
+        let consts = 1 + self.below(3);
+        let decls: Vec<String> = (0..consts)
+            .map(|i| format!("k{} = {}", i, self.literal_value()))
+            .collect();
+        out.push_str(&format!("const {};\n", decls.join(", ")));
+        self.consts = (0..consts).map(|i| format!("k{}", i)).collect();
+
+        self.vars = (0..3).map(|i| format!("v{}", i)).collect();
+        out.push_str("var v0, v1, v2, c0, c1;\n\n");
+
+        for i in 0..self.below(5) {
+            let routine = if self.below(2) == 0 {
+                self.routine(format!("f{}", i), true)
+            } else {
+                self.routine(format!("q{}", i), false)
+            };
+            out.push_str(&routine);
+        }
+
+        out.push_str(&self.compound(8, 0));
+        out.push_str(".\n");
+        out
+    }
+
+    /*
+     * A procedure or function declaration. Routines that are not nested
+     * too deeply declare routines of their own, which read and write
+     * their locals.
+     */
+    fn routine(&mut self, name: String, function: bool) -> String {
+        let pad = "    ".repeat(self.level);
+        let params: Vec<String> = (0..self.below(3))
+            .map(|i| format!("p{}{}", self.level, i))
+            .collect();
+        let local = format!("l{}", self.level);
+        let counter = match self.below(3) {
+            0 => Some(format!("d{}", self.level)),
+            _ => None,
+        };
+        let routine = Routine { name: name.clone(), arity: params.len(), counter };
+
+        // The counter is left out of `vars` so nothing but a recursive
+        // call can change it.
+        let scope = self.enter();
+        let enclosing = self.vars.last().filter(|_| self.routine).cloned();
+        self.vars.extend(params.iter().cloned());
+        self.vars.push(local.clone());
+        self.routine = true;
+        self.level += 1;
+
+        let mut nested = String::new();
+        if self.level < 3 {
+            for i in 0..self.below(3) {
+                let function = self.below(2) == 0;
+                let inner = self.routine(format!("{}_{}", name, i), function);
+                nested.push_str(&inner);
+            }
+        }
+
+        self.function = Some(name.clone()).filter(|_| function);
+        self.recursion = routine.counter.as_ref().map(|_| routine.clone());
+        let mut last = Vec::new();
+        if let Some(var) = enclosing {
+            last.push(format!("{} := {}", var, self.expr(2)));
+        }
+        // End with an assignment so a function's result is usually set.
+        if function {
+            last.push(format!("{} := {}", name, self.expr(2)));
+        }
+        let body = self.compound_with(3, self.level - 1, last);
+
+        self.level -= 1;
+        self.leave(scope);
+        let mut formals = params;
+        formals.extend(routine.counter.clone());
+        let kind = if function { "function" } else { "procedure" };
+        let decl = format!("{}{} {}{};\n{}var {};\n{}{}{};\n\n",
+                           pad, kind, name, param_list(&formals),
+                           pad, local, nested, pad, body);
+        if function {
+            self.functions.push(routine);
+        } else {
+            self.procedures.push(routine);
+        }
+        decl
+    }
+
+    fn enter(&mut self) -> Scope {
+        Scope {
+            vars: self.vars.clone(),
+            functions: self.functions.clone(),
+            procedures: self.procedures.clone(),
+            function: self.function.take(),
+            recursion: self.recursion.take(),
+            routine: self.routine,
+        }
+    }
+
+    fn leave(&mut self, scope: Scope) {
+        self.vars = scope.vars;
+        self.functions = scope.functions;
+        self.procedures = scope.procedures;
+        self.function = scope.function;
+        self.recursion = scope.recursion;
+        self.routine = scope.routine;
+    }
+
+    fn compound(&mut self, len: usize, indent: usize) -> String {
+        self.compound_with(len, indent, Vec::new())
+    }
+
+    /*
+     * A begin-end block of up to `len` random statements followed by the
+     * statements in `last`. The block itself starts at the current
+     * position.
+     */
+    fn compound_with(&mut self, len: usize, indent: usize, last: Vec<String>) -> String {
+        let pad = "    ".repeat(indent);
+        let mut stmts: Vec<String> = (0..1 + self.below(len))
+            .map(|_| self.statement(indent + 1))
+            .collect();
+        stmts.extend(last);
+        let stmts: Vec<String> = stmts.iter()
+            .map(|stmt| format!("{}    {}", pad, stmt))
+            .collect();
+        format!("begin\n{}\n{}end", stmts.join(";\n"), pad)
+    }
+
+    fn statement(&mut self, indent: usize) -> String {
+        match self.below(10) {
+            0 | 1 => {
+                let var = self.pick(&self.vars.clone());
+                format!("{} := {}", var, self.expr(3))
+            },
+            2 | 3 => format!("! {}", self.expr(3)),
+            4 => {
+                let cond = self.condition();
+                let then = self.statement(indent);
+                if self.below(2) == 0 {
+                    let otherwise = self.statement(indent);
+                    format!("if {} then {} else {}", cond, then, otherwise)
+                } else {
+                    format!("if {} then {}", cond, then)
+                }
+            },
+            5 if !self.procedures.is_empty() => {
+                let i = self.below(self.procedures.len());
+                let routine = self.procedures[i].clone();
+                format!("call {}{}", routine.name, self.args(&routine, true))
+            },
+            6 if !self.routine && self.loops < 2 => {
+                let counter = format!("c{}", self.loops);
+                let bound = 1 + self.below(4);
+                self.loops += 1;
+                let step = format!("{} := {} + 1", counter, counter);
+                let body = self.compound_with(3, indent, vec![step]);
+                self.loops -= 1;
+                format!("begin {} := 0; while {} < {} do {} end",
+                        counter, counter, bound, body)
+            },
+            7 if self.function.is_some() && self.below(3) == 0 => {
+                format!("return {}", self.expr(2))
+            },
+            7 if self.recursion.is_some() && self.below(2) == 0 => self.recurse(),
+            8 if self.below(4) == 0 => {
+                let var = match self.function.clone() {
+                    Some(name) if self.below(2) == 0 => name,
+                    _ => self.pick(&self.vars.clone()),
+                };
+                format!("? {}", var)
+            },
+            9 => self.compound(3, indent),
+            _ => format!("! {}", self.expr(2)),
+        }
+    }
+
+    /*
+     * A call of the recursive routine being generated, guarded by its
+     * counter.
+     */
+    fn recurse(&mut self) -> String {
+        let routine = self.recursion.clone().unwrap();
+        let counter = routine.counter.clone().unwrap();
+        let mut args: Vec<String> = (0..routine.arity).map(|_| self.expr(2)).collect();
+        args.push(format!("{} - 1", counter));
+        let call = format!("{}({})", routine.name, args.join(", "));
+        if self.function.is_some() {
+            let var = self.pick(&self.vars.clone());
+            format!("if {} > 0 then {} := {}", counter, var, call)
+        } else {
+            format!("if {} > 0 then call {}", counter, call)
+        }
+    }
+
+    fn condition(&mut self) -> String {
+        if self.below(4) == 0 {
+            return format!("odd {}", self.expr(2));
+        }
+        let ops = ["=", "#", "<", "<=", ">", ">="];
+        let op = ops[self.below(ops.len())];
+        format!("{} {} {}", self.expr(2), op, self.expr(2))
+    }
+
+    fn expr(&mut self, depth: usize) -> String {
+        let leaf = depth == 0 || self.below(3) == 0;
+        if leaf {
+            return match self.below(4) {
+                0 => self.literal(),
+                1 => self.pick(&self.consts.clone()),
+                _ => self.pick(&self.vars.clone()),
+            };
+        }
+
+        match self.below(8) {
+            0 if !self.functions.is_empty() => {
+                let i = self.below(self.functions.len());
+                let routine = self.functions[i].clone();
+                format!("{}{}", routine.name, self.args(&routine, false))
+            },
+            // A sign may only start an expression, so wrap it in a group.
+            1 => format!("(-({}))", self.expr(depth - 1)),
+            // Variables start at zero, so most divisors are forced to be
+            // odd to keep division by zero from ending most programs.
+            2 if self.below(4) != 0 => {
+                format!("({} / ({} * 2 + 1))", self.expr(depth - 1), self.expr(depth - 1))
+            },
+            _ => {
+                let ops = ["+", "-", "*", "*", "+", "-"];
+                let op = ops[self.below(ops.len())];
+                format!("({} {} {})", self.expr(depth - 1), op, self.expr(depth - 1))
+            },
+        }
+    }
+
+    /*
+     * An argument list. Procedures without parameters may be called
+     * without one, functions always need it. A recursive routine is
+     * started with a small counter.
+     */
+    fn args(&mut self, routine: &Routine, optional: bool) -> String {
+        let mut args: Vec<String> = (0..routine.arity).map(|_| self.expr(2)).collect();
+        if routine.counter.is_some() {
+            args.push(self.below(4).to_string());
+        }
+        if args.is_empty() && optional && self.below(2) == 0 {
+            String::new()
+        } else {
+            format!("({})", args.join(", "))
+        }
+    }
+
+    fn literal_value(&mut self) -> i64 {
+        match self.below(6) {
+            0 => 2_000_000_000 + self.below(100_000_000) as i64,
+            1 => 8_388_600 + self.below(20) as i64,
+            _ => self.below(20) as i64,
+        }
+    }
+
+    fn literal(&mut self) -> String {
+        let n = self.literal_value();
+        if self.below(4) == 0 {
+            format!("(0 - {})", n)
+        } else {
+            n.to_string()
+        }
+    }
+}
+
+fn param_list(params: &[String]) -> String {
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("({})", params.join(", "))
+    }
+}
@@ -0,0 +1,23 @@
+var i;
+
+function fib(n);
+begin
+    if n < 2 then return n;
+    fib := fib(n - 1) + fib(n - 2)
+end;
+
+function max(a, b);
+begin
+    max := a;
+    if b > a then max := b
+end;
+
+begin
+    i := 0;
+    while i <= 10 do
+    begin
+        ! fib(i);
+        i := i + 1
+    end;
+    ! max(3, 7) * max(4, 2)
+end.
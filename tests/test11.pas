@@ -0,0 +1,24 @@
+var n;
+
+function collatz(x);
+var steps;
+begin
+    steps := 0;
+    while x # 1 do
+    begin
+        if odd x then x := 3 * x + 1 else x := x / 2;
+        steps := steps + 1
+    end;
+    collatz := steps
+end;
+
+begin
+    n := 1;
+    while n <= 10 do
+    begin
+        if n = 7 then ! 0 - 1
+        else if n < 5 then ! collatz(n)
+        else ! n * 100;
+        n := n + 1
+    end
+end.
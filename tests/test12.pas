@@ -0,0 +1,11 @@
+const big = 2000000000, small = 8388608;
+var v;
+begin
+    v := (-3) + 2;
+    ! v;
+    ! -v * 5;
+    ! big;
+    ! 0 - big - 147483648;
+    ! small - 1;
+    ! -small
+end.
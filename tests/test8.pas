@@ -0,0 +1,20 @@
+var n, f;
+
+procedure fact;
+var m;
+begin
+    if n <= 1 then f := 1;
+    if n > 1 then
+    begin
+        m := n;
+        n := n - 1;
+        call fact;
+        f := f * m
+    end
+end;
+
+begin
+    n := 10;
+    call fact;
+    ! f
+end.
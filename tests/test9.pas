@@ -0,0 +1,23 @@
+var r;
+
+procedure gcd(a, b);
+begin
+    if b = 0 then r := a;
+    if b # 0 then call gcd(b, a - a / b * b)
+end;
+
+procedure hanoi(n, from, to, via);
+begin
+    if n > 0 then
+    begin
+        call hanoi(n - 1, from, via, to);
+        ! from * 10 + to;
+        call hanoi(n - 1, via, to, from)
+    end
+end;
+
+begin
+    call gcd(1071, 462);
+    ! r;
+    call hanoi(3, 1, 3, 2)
+end.